@@ -1,3 +1,4 @@
+use std::path::PathBuf;
 use serde::{Serialize, Deserialize};
 use crate::Backup;
 
@@ -6,15 +7,101 @@ pub struct ClientMessageCreateBackup {
     pub backup: Backup,
 }
 
+/// Replaces the definition of the backup called `name`, which may also be
+/// renamed by giving `backup` a different name.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientMessageUpdateBackup {
+    pub name: String,
+    pub backup: Backup,
+}
+
+/// Removes the backup definition; local repositories can also have their
+/// contents removed with `purge_repository`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientMessageDeleteBackup {
+    pub name: String,
+    #[serde(default)]
+    pub purge_repository: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ClientMessageRunBackup {
     pub name: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientMessageCancelBackup {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientMessageRestoreSnapshot {
+    pub name: String,
+    pub snapshot: String,
+    pub target: PathBuf,
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientMessageListSnapshots {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientMessageListSnapshotTree {
+    pub name: String,
+    pub snapshot: String,
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientMessageForgetSnapshots {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientMessageGetRunHistory {
+    pub name: String,
+    /// How many of the most recent runs to return, all of them if not set.
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+/// What the daemon logged about a backup, oldest first.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientMessageGetLogs {
+    pub name: String,
+    /// Only what was logged from this time on, as an RFC 3339 string.
+    #[serde(default)]
+    pub since: Option<String>,
+}
+
+/// Asks for the password and credentials of a backup, which are otherwise
+/// never sent to clients. Only root and the user the daemon runs as may.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientMessageRevealSecret {
+    pub name: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "message_type")]
 pub enum ClientMessage {
     CreateBackup(ClientMessageCreateBackup),
+    UpdateBackup(ClientMessageUpdateBackup),
+    DeleteBackup(ClientMessageDeleteBackup),
     ListBackups,
     RunBackup(ClientMessageRunBackup),
+    CancelBackup(ClientMessageCancelBackup),
+    RestoreSnapshot(ClientMessageRestoreSnapshot),
+    ListSnapshots(ClientMessageListSnapshots),
+    ListSnapshotTree(ClientMessageListSnapshotTree),
+    ForgetSnapshots(ClientMessageForgetSnapshots),
+    GetRunHistory(ClientMessageGetRunHistory),
+    GetLogs(ClientMessageGetLogs),
+    RevealSecret(ClientMessageRevealSecret),
+    /// Follows progress of every backup, until the connection is closed.
+    SubscribeEvents,
 }
@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use serde::{Serialize, Deserialize};
+use crate::RepositoryKind;
+
+/// A password or key. It goes over the wire as a plain string, since the
+/// daemon needs it, but Debug never shows it, so it does not end up in logs.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(secret: impl Into<String>) -> Self {
+        Secret(secret.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[redacted]")
+    }
+}
+
+impl From<String> for Secret {
+    fn from(secret: String) -> Self {
+        Secret(secret)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(secret: &str) -> Self {
+        Secret(secret.to_string())
+    }
+}
+
+/// What restic needs to get into the storage a repository lives in, which
+/// depends on its kind. Empty values are left out of the environment, so
+/// optional settings can be left empty.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Credentials {
+    #[default]
+    None,
+    B2 {
+        account_id: String,
+        account_key: Secret,
+    },
+    S3 {
+        access_key_id: String,
+        secret_access_key: Secret,
+        #[serde(default)]
+        region: String,
+    },
+    Rest {
+        username: String,
+        password: Secret,
+    },
+    Azure {
+        account_name: String,
+        account_key: Secret,
+    },
+    #[serde(rename = "gs")]
+    GCS {
+        project_id: String,
+        /// The service account key, as downloaded from the console.
+        credentials_file: PathBuf,
+    },
+    /// Keystone v3 authentication, which is what current OpenStack uses.
+    Swift {
+        auth_url: String,
+        username: String,
+        password: Secret,
+        #[serde(default)]
+        region_name: String,
+        #[serde(default)]
+        user_domain_name: String,
+        #[serde(default)]
+        project_name: String,
+        #[serde(default)]
+        project_domain_name: String,
+    },
+}
+
+impl Credentials {
+    /// Every environment variable credentials of any kind may use.
+    pub const VARIABLES: &'static [&'static str] = &[
+        "B2_ACCOUNT_ID", "B2_ACCOUNT_KEY",
+        "AWS_ACCESS_KEY_ID", "AWS_SECRET_ACCESS_KEY", "AWS_DEFAULT_REGION",
+        "RESTIC_REST_USERNAME", "RESTIC_REST_PASSWORD",
+        "AZURE_ACCOUNT_NAME", "AZURE_ACCOUNT_KEY",
+        "GOOGLE_PROJECT_ID", "GOOGLE_APPLICATION_CREDENTIALS",
+        "OS_AUTH_URL", "OS_USERNAME", "OS_PASSWORD", "OS_REGION_NAME",
+        "OS_USER_DOMAIN_NAME", "OS_PROJECT_NAME", "OS_PROJECT_DOMAIN_NAME",
+    ];
+
+    /// The kind of repository these are meant for, if any.
+    pub fn kind(&self) -> Option<RepositoryKind> {
+        match self {
+            Credentials::None => None,
+            Credentials::B2 { .. } => Some(RepositoryKind::B2),
+            Credentials::S3 { .. } => Some(RepositoryKind::S3),
+            Credentials::Rest { .. } => Some(RepositoryKind::Rest),
+            Credentials::Azure { .. } => Some(RepositoryKind::Azure),
+            Credentials::GCS { .. } => Some(RepositoryKind::GCS),
+            Credentials::Swift { .. } => Some(RepositoryKind::Swift),
+        }
+    }
+
+    /// The environment variables restic reads these from.
+    pub fn to_environment(&self) -> Vec<(&'static str, String)> {
+        let variables = match self {
+            Credentials::None => vec![],
+            Credentials::B2 { account_id, account_key } => vec![
+                ("B2_ACCOUNT_ID", account_id.clone()),
+                ("B2_ACCOUNT_KEY", account_key.expose().to_string()),
+            ],
+            Credentials::S3 { access_key_id, secret_access_key, region } => vec![
+                ("AWS_ACCESS_KEY_ID", access_key_id.clone()),
+                ("AWS_SECRET_ACCESS_KEY", secret_access_key.expose().to_string()),
+                ("AWS_DEFAULT_REGION", region.clone()),
+            ],
+            Credentials::Rest { username, password } => vec![
+                ("RESTIC_REST_USERNAME", username.clone()),
+                ("RESTIC_REST_PASSWORD", password.expose().to_string()),
+            ],
+            Credentials::Azure { account_name, account_key } => vec![
+                ("AZURE_ACCOUNT_NAME", account_name.clone()),
+                ("AZURE_ACCOUNT_KEY", account_key.expose().to_string()),
+            ],
+            Credentials::GCS { project_id, credentials_file } => vec![
+                ("GOOGLE_PROJECT_ID", project_id.clone()),
+                ("GOOGLE_APPLICATION_CREDENTIALS", credentials_file.to_string_lossy().to_string()),
+            ],
+            Credentials::Swift {
+                auth_url, username, password, region_name,
+                user_domain_name, project_name, project_domain_name,
+            } => vec![
+                ("OS_AUTH_URL", auth_url.clone()),
+                ("OS_USERNAME", username.clone()),
+                ("OS_PASSWORD", password.expose().to_string()),
+                ("OS_REGION_NAME", region_name.clone()),
+                ("OS_USER_DOMAIN_NAME", user_domain_name.clone()),
+                ("OS_PROJECT_NAME", project_name.clone()),
+                ("OS_PROJECT_DOMAIN_NAME", project_domain_name.clone()),
+            ],
+        };
+
+        variables.into_iter()
+            .filter(|(_, value)| !value.is_empty())
+            .collect()
+    }
+
+    /// Takes the credentials for the given kind of repository out of an
+    /// environment, as read back from a backup's configuration.
+    pub fn from_environment(kind: &RepositoryKind, environment: &mut HashMap<String, String>) -> Self {
+        let mut take = |name: &str| environment.remove(name).unwrap_or_default();
+        let credentials = match kind {
+            RepositoryKind::Local | RepositoryKind::SFTP | RepositoryKind::Rclone => Credentials::None,
+            RepositoryKind::B2 => Credentials::B2 {
+                account_id: take("B2_ACCOUNT_ID"),
+                account_key: take("B2_ACCOUNT_KEY").into(),
+            },
+            RepositoryKind::S3 => Credentials::S3 {
+                access_key_id: take("AWS_ACCESS_KEY_ID"),
+                secret_access_key: take("AWS_SECRET_ACCESS_KEY").into(),
+                region: take("AWS_DEFAULT_REGION"),
+            },
+            RepositoryKind::Rest => Credentials::Rest {
+                username: take("RESTIC_REST_USERNAME"),
+                password: take("RESTIC_REST_PASSWORD").into(),
+            },
+            RepositoryKind::Azure => Credentials::Azure {
+                account_name: take("AZURE_ACCOUNT_NAME"),
+                account_key: take("AZURE_ACCOUNT_KEY").into(),
+            },
+            RepositoryKind::GCS => Credentials::GCS {
+                project_id: take("GOOGLE_PROJECT_ID"),
+                credentials_file: take("GOOGLE_APPLICATION_CREDENTIALS").into(),
+            },
+            RepositoryKind::Swift => Credentials::Swift {
+                auth_url: take("OS_AUTH_URL"),
+                username: take("OS_USERNAME"),
+                password: take("OS_PASSWORD").into(),
+                region_name: take("OS_REGION_NAME"),
+                user_domain_name: take("OS_USER_DOMAIN_NAME"),
+                project_name: take("OS_PROJECT_NAME"),
+                project_domain_name: take("OS_PROJECT_DOMAIN_NAME"),
+            },
+        };
+
+        if credentials.to_environment().is_empty() {
+            Credentials::None
+        } else {
+            credentials
+        }
+    }
+
+    /// The same credentials with their secrets left empty, for clients that
+    /// only need to know how the repository is reached.
+    pub fn redacted(&self) -> Self {
+        let mut credentials = self.clone();
+        for secret in credentials.secrets_mut() {
+            *secret = Secret::default();
+        }
+        credentials
+    }
+
+    /// Takes the secrets of `previous` where these leave them empty, but
+    /// only if everything else is the same, so they are never sent to an
+    /// endpoint or account they were not given for.
+    pub fn keep_secrets(&mut self, previous: &Credentials) {
+        if self.redacted() != previous.redacted() {
+            return;
+        }
+
+        let mut previous = previous.clone();
+        for (secret, previous) in self.secrets_mut().into_iter().zip(previous.secrets_mut()) {
+            if secret.is_empty() {
+                *secret = std::mem::take(previous);
+            }
+        }
+    }
+
+    /// Whether these leave empty a secret that `previous` has.
+    pub fn lack_secrets_of(&self, previous: &Credentials) -> bool {
+        if self.kind() != previous.kind() {
+            return false;
+        }
+
+        let (mut credentials, mut previous) = (self.clone(), previous.clone());
+        credentials.secrets_mut().into_iter().zip(previous.secrets_mut())
+            .any(|(secret, previous)| secret.is_empty() && !previous.is_empty())
+    }
+
+    fn secrets_mut(&mut self) -> Vec<&mut Secret> {
+        match self {
+            Credentials::None | Credentials::GCS { .. } => vec![],
+            Credentials::B2 { account_key, .. } |
+            Credentials::Azure { account_key, .. } => vec![account_key],
+            Credentials::S3 { secret_access_key, .. } => vec![secret_access_key],
+            Credentials::Rest { password, .. } |
+            Credentials::Swift { password, .. } => vec![password],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn credentials_environment() {
+        let credentials = Credentials::S3 {
+            access_key_id: "minio".to_string(),
+            secret_access_key: "minio123".into(),
+            region: String::new(),
+        };
+        assert_eq!(
+            credentials.to_environment(),
+            vec![
+                ("AWS_ACCESS_KEY_ID", "minio".to_string()),
+                ("AWS_SECRET_ACCESS_KEY", "minio123".to_string()),
+            ]
+        );
+
+        let mut environment: HashMap<String, String> = credentials.to_environment().into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect();
+        environment.insert("RESTIC_CACERT".to_string(), "/etc/ca.pem".to_string());
+        assert_eq!(Credentials::from_environment(&RepositoryKind::S3, &mut environment), credentials);
+        assert_eq!(environment.len(), 1);
+
+        assert_eq!(
+            Credentials::from_environment(&RepositoryKind::Rest, &mut HashMap::new()),
+            Credentials::None
+        );
+
+        let credentials: Credentials = serde_json::from_value(json!({
+            "kind": "gs",
+            "project_id": "backups",
+            "credentials_file": "/etc/gcs.json",
+        })).unwrap();
+        assert_eq!(credentials.kind(), Some(RepositoryKind::GCS));
+
+        let swift = Credentials::Swift {
+            auth_url: "a".to_string(),
+            username: "b".to_string(),
+            password: "c".into(),
+            region_name: "d".to_string(),
+            user_domain_name: "e".to_string(),
+            project_name: "f".to_string(),
+            project_domain_name: "g".to_string(),
+        };
+        let all = vec![
+            swift,
+            Credentials::B2 { account_id: "a".to_string(), account_key: "b".into() },
+            Credentials::S3 { access_key_id: "a".to_string(), secret_access_key: "b".into(), region: "c".to_string() },
+            Credentials::Rest { username: "a".to_string(), password: "b".into() },
+            Credentials::Azure { account_name: "a".to_string(), account_key: "b".into() },
+            Credentials::GCS { project_id: "a".to_string(), credentials_file: "/b".into() },
+        ];
+        for credentials in all {
+            for (variable, _) in credentials.to_environment() {
+                assert!(Credentials::VARIABLES.contains(&variable), "{} is missing", variable);
+            }
+        }
+    }
+
+    #[test]
+    fn secrets() {
+        let credentials = Credentials::B2 {
+            account_id: "key-id".to_string(),
+            account_key: "very-secret".into(),
+        };
+        assert!(!format!("{:?}", credentials).contains("very-secret"));
+        assert_eq!(serde_json::to_value(&credentials).unwrap()["account_key"], "very-secret");
+
+        let mut redacted = credentials.redacted();
+        assert_eq!(redacted, Credentials::B2 { account_id: "key-id".to_string(), account_key: Secret::default() });
+
+        redacted.keep_secrets(&credentials);
+        assert_eq!(redacted, credentials);
+
+        let mut other = Credentials::Azure { account_name: "name".to_string(), account_key: Secret::default() };
+        other.keep_secrets(&credentials);
+        assert_eq!(other.to_environment(), vec![("AZURE_ACCOUNT_NAME", "name".to_string())]);
+        assert!(!other.lack_secrets_of(&credentials));
+
+        let mut moved = Credentials::B2 { account_id: "other-id".to_string(), account_key: Secret::default() };
+        moved.keep_secrets(&credentials);
+        assert!(moved.lack_secrets_of(&credentials));
+    }
+}
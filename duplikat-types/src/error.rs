@@ -2,14 +2,151 @@ use std::fmt;
 use serde::{Serialize, Deserialize};
 use thiserror::Error;
 
-#[derive(Error, Serialize, Deserialize, Debug)]
-pub enum ServerError {
-    Configuration(String),
-    RepoInit(String)
+/// What the daemon was doing when something went wrong.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    CreateBackup,
+    InitRepository,
+    UpdateBackup,
+    DeleteBackup,
+    RunBackup,
+    CancelBackup,
+    RestoreSnapshot,
+    ListSnapshots,
+    ListSnapshotTree,
+    ForgetSnapshots,
+    GetRunHistory,
+    GetLogs,
+    RevealSecret,
 }
 
-impl fmt::Display for ServerError {
+impl fmt::Display for Operation {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+        let description = match self {
+            Operation::CreateBackup => "create the backup",
+            Operation::InitRepository => "initialize the repository",
+            Operation::UpdateBackup => "update the backup",
+            Operation::DeleteBackup => "delete the backup",
+            Operation::RunBackup => "run the backup",
+            Operation::CancelBackup => "cancel the backup",
+            Operation::RestoreSnapshot => "restore the snapshot",
+            Operation::ListSnapshots => "list snapshots",
+            Operation::ListSnapshotTree => "list the contents of the snapshot",
+            Operation::ForgetSnapshots => "forget snapshots",
+            Operation::GetRunHistory => "get the run history",
+            Operation::GetLogs => "get the logs",
+            Operation::RevealSecret => "read the secrets of the backup",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// Sent as `{"code": ..., ...}`, so clients can tell errors apart without
+/// looking at the messages, which are meant for people. Where restic had
+/// something to say about the problem, it is kept as the detail.
+#[derive(Error, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub enum ServerError {
+    #[error("There is no backup called {name}.")]
+    NotFound { name: String },
+    #[error("There already is a backup called {name}.")]
+    AlreadyExists { name: String },
+    #[error("{name:?} cannot be used as a name: {reason}.")]
+    InvalidName { name: String, reason: String },
+    #[error("The password for the repository is wrong.")]
+    WrongPassword { detail: String },
+    #[error("The repository is locked by another restic process.")]
+    RepositoryLocked { detail: String },
+    #[error("The repository could not be reached.")]
+    RepositoryUnreachable { detail: String },
+    #[error("The storage service did not accept the credentials.")]
+    BackendAuth { detail: String },
+    #[error("restic could not be found, make sure it is installed.")]
+    ResticMissing,
+    /// The backup is running, and what was asked cannot be done meanwhile.
+    #[error("{name} is busy running a backup.")]
+    Busy { name: String },
+    #[error("Permission denied: {detail}")]
+    PermissionDenied { detail: String },
+    /// The snapshot was made, but some files could not be read.
+    #[error("Some files could not be backed up.")]
+    IncompleteSnapshot { detail: String },
+    #[error("Bad request: {detail}")]
+    BadRequest { detail: String },
+    /// Anything that does not fit the above.
+    #[error("Failed to {operation}: {detail}")]
+    Failed { operation: Operation, detail: String },
+}
+
+impl ServerError {
+    /// The machine-readable code, as found in the serialized error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ServerError::NotFound { .. } => "not_found",
+            ServerError::AlreadyExists { .. } => "already_exists",
+            ServerError::InvalidName { .. } => "invalid_name",
+            ServerError::WrongPassword { .. } => "wrong_password",
+            ServerError::RepositoryLocked { .. } => "repository_locked",
+            ServerError::RepositoryUnreachable { .. } => "repository_unreachable",
+            ServerError::BackendAuth { .. } => "backend_auth",
+            ServerError::ResticMissing => "restic_missing",
+            ServerError::Busy { .. } => "busy",
+            ServerError::PermissionDenied { .. } => "permission_denied",
+            ServerError::IncompleteSnapshot { .. } => "incomplete_snapshot",
+            ServerError::BadRequest { .. } => "bad_request",
+            ServerError::Failed { .. } => "failed",
+        }
+    }
+
+    /// What restic or the system said about the problem, for those who want
+    /// to dig deeper than the message.
+    pub fn detail(&self) -> Option<&str> {
+        match self {
+            ServerError::WrongPassword { detail } |
+            ServerError::RepositoryLocked { detail } |
+            ServerError::RepositoryUnreachable { detail } |
+            ServerError::BackendAuth { detail } |
+            ServerError::IncompleteSnapshot { detail } => Some(detail),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn server_error() {
+        let error = ServerError::NotFound { name: "home".to_string() };
+        assert_eq!(serde_json::to_value(&error).unwrap(), json!({"code": "not_found", "name": "home"}));
+        assert_eq!(error.to_string(), "There is no backup called home.");
+
+        let error: ServerError = serde_json::from_value(json!({
+            "code": "failed",
+            "operation": "list_snapshots",
+            "detail": "Fatal: unable to open config file"
+        })).unwrap();
+        assert_eq!(error.to_string(), "Failed to list snapshots: Fatal: unable to open config file");
+
+        let errors = vec![
+            error,
+            ServerError::AlreadyExists { name: "home".to_string() },
+            ServerError::InvalidName { name: "a/b".to_string(), reason: "it contains a slash".to_string() },
+            ServerError::WrongPassword { detail: String::new() },
+            ServerError::RepositoryLocked { detail: String::new() },
+            ServerError::RepositoryUnreachable { detail: String::new() },
+            ServerError::BackendAuth { detail: String::new() },
+            ServerError::ResticMissing,
+            ServerError::Busy { name: "home".to_string() },
+            ServerError::PermissionDenied { detail: String::new() },
+            ServerError::IncompleteSnapshot { detail: String::new() },
+            ServerError::BadRequest { detail: String::new() },
+        ];
+        for error in errors {
+            assert_eq!(serde_json::to_value(&error).unwrap()["code"], error.code());
+        }
     }
 }
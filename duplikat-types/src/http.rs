@@ -0,0 +1,314 @@
+use percent_encoding::{AsciiSet, CONTROLS, percent_decode_str, utf8_percent_encode};
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use crate::*;
+
+/// Characters that cannot appear as-is in a path segment or query value.
+const COMPONENT: &AsciiSet = &CONTROLS
+    .add(b' ').add(b'"').add(b'#').add(b'%').add(b'&').add(b'+')
+    .add(b'/').add(b'<').add(b'>').add(b'?').add(b'`').add(b'{').add(b'}');
+
+/// Body of the restore route, the backup and snapshot are part of the path.
+#[derive(Serialize, Deserialize, Debug)]
+struct RestoreOptions {
+    target: std::path::PathBuf,
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct HttpRequest {
+    pub method: &'static str,
+    pub path: String,
+    pub body: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum HttpRequestError {
+    #[error("No such route: {0} {1}")]
+    NotFound(String, String),
+    #[error("Bad request body: {0}")]
+    BadBody(#[from] serde_json::Error),
+}
+
+fn encode(component: &str) -> String {
+    utf8_percent_encode(component, COMPONENT).to_string()
+}
+
+fn decode(component: &str) -> String {
+    percent_decode_str(component).decode_utf8_lossy().to_string()
+}
+
+impl ClientMessage {
+    /// The REST-style route for this message, as served by duplikatd.
+    pub fn to_http_request(&self) -> HttpRequest {
+        let backup_path = |name: &str| format!("/v1/backups/{}", encode(name));
+        let (method, path, body) = match self {
+            ClientMessage::ListBackups => ("GET", "/v1/backups".to_string(), None),
+            ClientMessage::SubscribeEvents => ("GET", "/v1/events".to_string(), None),
+            ClientMessage::CreateBackup(create) => (
+                "POST", "/v1/backups".to_string(),
+                Some(serde_json::to_string(&create.backup).unwrap()),
+            ),
+            ClientMessage::UpdateBackup(update) => (
+                "PUT", backup_path(&update.name),
+                Some(serde_json::to_string(&update.backup).unwrap()),
+            ),
+            ClientMessage::DeleteBackup(delete) => (
+                "DELETE",
+                format!("{}?purge_repository={}", backup_path(&delete.name), delete.purge_repository),
+                None,
+            ),
+            ClientMessage::RunBackup(run) => (
+                "POST", format!("{}/runs", backup_path(&run.name)), None,
+            ),
+            ClientMessage::GetRunHistory(history) => (
+                "GET",
+                match history.limit {
+                    Some(limit) => format!("{}/runs?limit={}", backup_path(&history.name), limit),
+                    None => format!("{}/runs", backup_path(&history.name)),
+                },
+                None,
+            ),
+            ClientMessage::GetLogs(logs) => (
+                "GET",
+                match &logs.since {
+                    Some(since) => format!("{}/logs?since={}", backup_path(&logs.name), encode(since)),
+                    None => format!("{}/logs", backup_path(&logs.name)),
+                },
+                None,
+            ),
+            ClientMessage::RevealSecret(reveal) => (
+                "GET", format!("{}/secret", backup_path(&reveal.name)), None,
+            ),
+            ClientMessage::CancelBackup(cancel) => (
+                "DELETE", format!("{}/runs/current", backup_path(&cancel.name)), None,
+            ),
+            ClientMessage::ListSnapshots(list) => (
+                "GET", format!("{}/snapshots", backup_path(&list.name)), None,
+            ),
+            ClientMessage::ListSnapshotTree(tree) => (
+                "GET",
+                format!("{}/snapshots/{}/tree?path={}",
+                    backup_path(&tree.name), encode(&tree.snapshot), encode(&tree.path)
+                ),
+                None,
+            ),
+            ClientMessage::RestoreSnapshot(restore) => (
+                "POST",
+                format!("{}/snapshots/{}/restore", backup_path(&restore.name), encode(&restore.snapshot)),
+                Some(serde_json::to_string(&RestoreOptions {
+                    target: restore.target.clone(),
+                    include: restore.include.clone(),
+                    exclude: restore.exclude.clone(),
+                }).unwrap()),
+            ),
+            ClientMessage::ForgetSnapshots(forget) => (
+                "POST", format!("{}/forget", backup_path(&forget.name)), None,
+            ),
+        };
+
+        HttpRequest { method, path, body }
+    }
+
+    /// Maps a request made to one of the routes above back into a message.
+    pub fn from_http_request(method: &str, path: &str, body: &[u8]) -> Result<Self, HttpRequestError> {
+        let (path_only, query) = path.split_once('?').unwrap_or((path, ""));
+        let segments: Vec<String> = path_only.split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(decode)
+            .collect();
+        let segments: Vec<&str> = segments.iter().map(|s| s.as_str()).collect();
+
+        let query_value = |key: &str| query.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(k, _)| *k == key)
+            .map(|(_, value)| decode(value));
+
+        let message = match (method, segments.as_slice()) {
+            ("GET", ["v1", "backups"]) => ClientMessage::ListBackups,
+            ("GET", ["v1", "events"]) => ClientMessage::SubscribeEvents,
+            ("POST", ["v1", "backups"]) => ClientMessage::CreateBackup(
+                ClientMessageCreateBackup { backup: serde_json::from_slice(body)? }
+            ),
+            ("PUT", ["v1", "backups", name]) => ClientMessage::UpdateBackup(
+                ClientMessageUpdateBackup {
+                    name: name.to_string(),
+                    backup: serde_json::from_slice(body)?,
+                }
+            ),
+            ("DELETE", ["v1", "backups", name]) => ClientMessage::DeleteBackup(
+                ClientMessageDeleteBackup {
+                    name: name.to_string(),
+                    purge_repository: query_value("purge_repository").as_deref() == Some("true"),
+                }
+            ),
+            ("POST", ["v1", "backups", name, "runs"]) => ClientMessage::RunBackup(
+                ClientMessageRunBackup { name: name.to_string() }
+            ),
+            ("GET", ["v1", "backups", name, "runs"]) => ClientMessage::GetRunHistory(
+                ClientMessageGetRunHistory {
+                    name: name.to_string(),
+                    limit: query_value("limit").and_then(|limit| limit.parse().ok()),
+                }
+            ),
+            ("GET", ["v1", "backups", name, "logs"]) => ClientMessage::GetLogs(
+                ClientMessageGetLogs {
+                    name: name.to_string(),
+                    since: query_value("since"),
+                }
+            ),
+            ("GET", ["v1", "backups", name, "secret"]) => ClientMessage::RevealSecret(
+                ClientMessageRevealSecret { name: name.to_string() }
+            ),
+            ("DELETE", ["v1", "backups", name, "runs", "current"]) => ClientMessage::CancelBackup(
+                ClientMessageCancelBackup { name: name.to_string() }
+            ),
+            ("GET", ["v1", "backups", name, "snapshots"]) => ClientMessage::ListSnapshots(
+                ClientMessageListSnapshots { name: name.to_string() }
+            ),
+            ("GET", ["v1", "backups", name, "snapshots", snapshot, "tree"]) => ClientMessage::ListSnapshotTree(
+                ClientMessageListSnapshotTree {
+                    name: name.to_string(),
+                    snapshot: snapshot.to_string(),
+                    path: query_value("path").unwrap_or_else(|| "/".to_string()),
+                }
+            ),
+            ("POST", ["v1", "backups", name, "snapshots", snapshot, "restore"]) => {
+                let options: RestoreOptions = serde_json::from_slice(body)?;
+                ClientMessage::RestoreSnapshot(ClientMessageRestoreSnapshot {
+                    name: name.to_string(),
+                    snapshot: snapshot.to_string(),
+                    target: options.target,
+                    include: options.include,
+                    exclude: options.exclude,
+                })
+            },
+            ("POST", ["v1", "backups", name, "forget"]) => ClientMessage::ForgetSnapshots(
+                ClientMessageForgetSnapshots { name: name.to_string() }
+            ),
+            _ => return Err(HttpRequestError::NotFound(method.to_string(), path.to_string())),
+        };
+
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(message: ClientMessage) -> ClientMessage {
+        let request = message.to_http_request();
+        ClientMessage::from_http_request(
+            request.method,
+            &request.path,
+            request.body.unwrap_or_default().as_bytes()
+        ).unwrap()
+    }
+
+    #[test]
+    fn routes() {
+        let request = ClientMessage::CancelBackup(
+            ClientMessageCancelBackup { name: "System backup".to_string() }
+        ).to_http_request();
+        assert_eq!(
+            request,
+            HttpRequest {
+                method: "DELETE",
+                path: "/v1/backups/System%20backup/runs/current".to_string(),
+                body: None,
+            }
+        );
+
+        if let ClientMessage::ListSnapshotTree(tree) = round_trip(ClientMessage::ListSnapshotTree(
+            ClientMessageListSnapshotTree {
+                name: "a/b".to_string(),
+                snapshot: "abcdef".to_string(),
+                path: "/home/kov/100% & more".to_string(),
+            }
+        )) {
+            assert_eq!(tree.name, "a/b");
+            assert_eq!(tree.path, "/home/kov/100% & more");
+        } else {
+            panic!("Expected a snapshot tree request");
+        }
+
+        if let ClientMessage::GetLogs(logs) = round_trip(ClientMessage::GetLogs(
+            ClientMessageGetLogs {
+                name: "home".to_string(),
+                since: Some("2021-07-04T10:00:00+02:00".to_string()),
+            }
+        )) {
+            assert_eq!(logs.since.as_deref(), Some("2021-07-04T10:00:00+02:00"));
+        } else {
+            panic!("Expected a logs request");
+        }
+
+        if let ClientMessage::RestoreSnapshot(restore) = round_trip(ClientMessage::RestoreSnapshot(
+            ClientMessageRestoreSnapshot {
+                name: "home".to_string(),
+                snapshot: "abcdef".to_string(),
+                target: "/tmp/restore".into(),
+                include: vec!["/home/kov/Documents".to_string()],
+                exclude: vec![],
+            }
+        )) {
+            assert_eq!(restore.snapshot, "abcdef");
+            assert_eq!(restore.include, vec!["/home/kov/Documents".to_string()]);
+        } else {
+            panic!("Expected a restore request");
+        }
+
+        if let ClientMessage::UpdateBackup(update) = round_trip(ClientMessage::UpdateBackup(
+            ClientMessageUpdateBackup {
+                name: "home".to_string(),
+                backup: Backup {
+                    name: "Home folder".to_string(),
+                    repository: "/srv/backups/home".parse().unwrap(),
+                    credentials: Credentials::None,
+                    ca_cert: None,
+                    client_cert: None,
+                    rclone: None,
+                    password: "pass".into(),
+                    include: vec!["/home/kov".into()],
+                    exclude: vec![],
+                    retention: None,
+                    schedule: None,
+                },
+            }
+        )) {
+            assert_eq!(update.name, "home");
+            assert_eq!(update.backup.name, "Home folder");
+        } else {
+            panic!("Expected an update request");
+        }
+
+        if let ClientMessage::DeleteBackup(delete) = round_trip(ClientMessage::DeleteBackup(
+            ClientMessageDeleteBackup { name: "home".to_string(), purge_repository: true }
+        )) {
+            assert!(delete.purge_repository);
+        } else {
+            panic!("Expected a delete request");
+        }
+
+        if let Ok(ClientMessage::DeleteBackup(delete)) = ClientMessage::from_http_request(
+            "DELETE", "/v1/backups/home", b""
+        ) {
+            assert!(!delete.purge_repository);
+        } else {
+            panic!("Expected a delete request");
+        }
+
+        assert!(matches!(
+            ClientMessage::from_http_request("PUT", "/v1/backups", b""),
+            Err(HttpRequestError::NotFound(_, _))
+        ));
+        assert!(matches!(
+            ClientMessage::from_http_request("POST", "/v1/backups", b"{}"),
+            Err(HttpRequestError::BadBody(_))
+        ));
+    }
+}
@@ -1,21 +1,47 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{convert::TryFrom, fmt, path::PathBuf, str::FromStr};
 use serde::{Serialize, Deserialize};
-use strum_macros::{EnumIter, EnumString, ToString};
+use strum_macros::{Display, EnumIter, EnumString};
+use thiserror::Error;
 
 mod client;
+mod credentials;
 mod error;
+mod http;
 mod server;
 pub use crate::client::*;
+pub use crate::credentials::*;
 pub use crate::error::*;
+pub use crate::http::*;
 pub use crate::server::*;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, EnumIter, EnumString, ToString)]
+/// Where a system-wide duplikatd listens for local clients.
+#[cfg(target_os = "macos")]
+pub const SYSTEM_SOCKET_PATH: &str = "/var/run/duplikatd/duplikatd.sock";
+#[cfg(not(target_os = "macos"))]
+pub const SYSTEM_SOCKET_PATH: &str = "/run/duplikatd/duplikatd.sock";
+
+/// Name of the socket a per-user duplikatd creates in the user's runtime directory.
+pub const USER_SOCKET_NAME: &str = "duplikatd.sock";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, EnumIter, EnumString, Display)]
 #[serde(rename_all = "lowercase")]
 #[strum(serialize_all = "snake_case")]
 pub enum RepositoryKind {
     Local,
     SFTP,
     B2,
+    /// Amazon S3 or anything speaking its protocol, like MinIO.
+    S3,
+    /// restic's own rest-server.
+    Rest,
+    /// Anything rclone can reach, through one of its configured remotes.
+    Rclone,
+    Azure,
+    #[serde(rename = "gs")]
+    #[strum(serialize = "gs")]
+    GCS,
+    /// OpenStack Swift.
+    Swift,
 }
 
 impl RepositoryKind {
@@ -25,74 +51,453 @@ impl RepositoryKind {
             RepositoryKind::Local => "Local Directory",
             RepositoryKind::SFTP => "SFTP",
             RepositoryKind::B2 => "Backblaze B2",
+            RepositoryKind::S3 => "Amazon S3 or compatible",
+            RepositoryKind::Rest => "REST Server",
+            RepositoryKind::Rclone => "rclone",
+            RepositoryKind::Azure => "Azure Blob Storage",
+            RepositoryKind::GCS => "Google Cloud Storage",
+            RepositoryKind::Swift => "OpenStack Swift",
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Repository {
     pub kind: RepositoryKind,
     pub identifier: String,
     pub path: String,
 }
 
-impl ToString for Repository {
-    fn to_string(&self) -> String {
+/// Why a string could not be understood as a repository.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum RepositoryParseError {
+    #[error("No repository given")]
+    Empty,
+    #[error("{0:?} is neither an absolute path nor of the form kind:location")]
+    Malformed(String),
+    #[error("Unknown kind of repository: {0:?}")]
+    UnknownKind(String),
+    #[error("No host or bucket given for the {0} repository")]
+    MissingIdentifier(RepositoryKind),
+    #[error("Local repositories do not take a host or bucket")]
+    UnexpectedIdentifier,
+    #[error("{0:?} cannot be used as a host or bucket, it has a ':' outside of brackets")]
+    BadIdentifier(String),
+    #[error("No path given for the repository")]
+    MissingPath,
+    #[error("No bucket given for the repository")]
+    MissingBucket,
+    #[error("{0:?} cannot be used as an endpoint, it has a '/' after the host")]
+    BadEndpoint(String),
+    #[error("{0:?} is not an http:// or https:// URL")]
+    MissingScheme(String),
+    #[error("Repositories cannot contain control characters")]
+    ControlCharacter,
+}
+
+/// S3 endpoints may start with a scheme, for servers like MinIO that do not
+/// speak HTTPS; restic defaults to it otherwise. REST ones always have one.
+fn strip_scheme(endpoint: &str) -> &str {
+    endpoint.strip_prefix("http://")
+        .or_else(|| endpoint.strip_prefix("https://"))
+        .unwrap_or(endpoint)
+}
+
+/// Where the identifier ends, which is the first ':' that is not part of an
+/// IPv6 address in brackets.
+fn identifier_end(string: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (index, character) in string.char_indices() {
+        match character {
+            '[' => depth += 1,
+            ']' if depth > 0 => depth -= 1,
+            ':' if depth == 0 => return Some(index),
+            _ => (),
+        }
+    }
+    None
+}
+
+impl Repository {
+    /// Checks the repository can be written out and read back as it is.
+    pub fn validate(&self) -> Result<(), RepositoryParseError> {
+        if self.identifier.chars().chain(self.path.chars()).any(char::is_control) {
+            return Err(RepositoryParseError::ControlCharacter);
+        }
+
         match self.kind {
-            RepositoryKind::Local => self.path.clone(),
-            _ => format!("{}:{}:{}",
-                self.kind.to_string(), self.identifier, self.path
-            ),
+            RepositoryKind::Local => {
+                if !self.identifier.is_empty() {
+                    return Err(RepositoryParseError::UnexpectedIdentifier);
+                }
+            },
+            RepositoryKind::S3 => {
+                let host = strip_scheme(&self.identifier);
+                if host.is_empty() {
+                    return Err(RepositoryParseError::MissingIdentifier(self.kind.clone()));
+                }
+                if host.contains('/') {
+                    return Err(RepositoryParseError::BadEndpoint(self.identifier.clone()));
+                }
+                if self.path.is_empty() || self.path.starts_with('/') {
+                    return Err(RepositoryParseError::MissingBucket);
+                }
+            },
+            RepositoryKind::Rest => {
+                let host = strip_scheme(&self.identifier);
+                if host.len() == self.identifier.len() {
+                    return Err(RepositoryParseError::MissingScheme(self.identifier.clone()));
+                }
+                if host.is_empty() {
+                    return Err(RepositoryParseError::MissingIdentifier(self.kind.clone()));
+                }
+                if host.contains('/') {
+                    return Err(RepositoryParseError::BadEndpoint(self.identifier.clone()));
+                }
+            },
+            _ => {
+                if self.identifier.is_empty() {
+                    return Err(RepositoryParseError::MissingIdentifier(self.kind.clone()));
+                }
+                if identifier_end(&self.identifier).is_some() {
+                    return Err(RepositoryParseError::BadIdentifier(self.identifier.clone()));
+                }
+            },
         }
+
+        // The root of a bucket, server or remote is a fine place for a repository.
+        let root_allowed = matches!(
+            self.kind,
+            RepositoryKind::B2 | RepositoryKind::Rest | RepositoryKind::Rclone
+        );
+        if self.path.is_empty() && !root_allowed {
+            return Err(RepositoryParseError::MissingPath);
+        }
+
+        Ok(())
     }
 }
 
-impl From<&str> for Repository {
-    fn from(string: &str) -> Self {
-        if string.starts_with('/') {
+impl fmt::Display for Repository {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            RepositoryKind::Local if self.path.starts_with('/') => write!(f, "{}", self.path),
+            RepositoryKind::Local => write!(f, "local:{}", self.path),
+            RepositoryKind::S3 | RepositoryKind::Rest => {
+                write!(f, "{}:{}/{}", self.kind, self.identifier, self.path)
+            },
+            _ => write!(f, "{}:{}:{}", self.kind, self.identifier, self.path),
+        }
+    }
+}
+
+impl FromStr for Repository {
+    type Err = RepositoryParseError;
+
+    /// Reads restic's repository strings: `/path` or `local:path`, and
+    /// `s3:endpoint/bucket/prefix`, `rest:url/path` and `kind:identifier:path`
+    /// for the rest.
+    /// Paths may have colons in them, identifiers only inside brackets, as in
+    /// `sftp:user@[::1]:/srv`.
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        if string.is_empty() {
+            return Err(RepositoryParseError::Empty);
+        }
+
+        let repository = if string.starts_with('/') {
             Repository {
                 kind: RepositoryKind::Local,
                 identifier: "".to_string(),
                 path: string.to_string(),
             }
         } else {
-            let mut parts = string.split(':');
+            let (kind, rest) = string.split_once(':')
+                .ok_or_else(|| RepositoryParseError::Malformed(string.to_string()))?;
+            let kind = RepositoryKind::from_str(kind)
+                .map_err(|_| RepositoryParseError::UnknownKind(kind.to_string()))?;
 
-            let kind = parts.next()
-                .expect("Malformed repository string: expected ':', not found");
-            let identifier = parts.next()
-                .expect("Malformed repository string: no identifier found")
-                .to_string();
-            let path = parts.next()
-                .expect("Malformed repository string: no path")
-                .to_string();
+            match kind {
+                RepositoryKind::Local => Repository {
+                    kind,
+                    identifier: "".to_string(),
+                    path: rest.to_string(),
+                },
+                RepositoryKind::S3 | RepositoryKind::Rest => {
+                    let scheme = rest.len() - strip_scheme(rest).len();
+                    let (identifier, path) = match rest[scheme..].find('/') {
+                        Some(end) => (&rest[..scheme + end], &rest[scheme + end + 1..]),
+                        None => (rest, ""),
+                    };
+                    Repository {
+                        kind,
+                        identifier: identifier.to_string(),
+                        path: path.to_string(),
+                    }
+                },
+                _ => {
+                    let end = identifier_end(rest)
+                        .ok_or(RepositoryParseError::MissingPath)?;
+                    Repository {
+                        kind,
+                        identifier: rest[..end].to_string(),
+                        path: rest[end + 1..].to_string(),
+                    }
+                },
+            }
+        };
 
-            let kind = RepositoryKind::from_str(kind)
-                .unwrap_or_else(|_| panic!("Bad kind name: {}", kind));
+        repository.validate()?;
+        Ok(repository)
+    }
+}
 
-            Repository {
-                kind,
-                identifier,
-                path,
+impl TryFrom<&str> for Repository {
+    type Error = RepositoryParseError;
+
+    fn try_from(string: &str) -> Result<Self, Self::Error> {
+        string.parse()
+    }
+}
+
+/// Settings for repositories reached through rclone. Which rclone is run, and
+/// how, is up to the daemon's configuration, as clients could otherwise have it
+/// run anything.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RcloneOptions {
+    /// Where rclone finds its remotes, instead of the user's rclone.conf.
+    pub config: Option<PathBuf>,
+}
+
+/// Which snapshots to keep when forgetting old ones, mirroring restic's own
+/// `--keep-*` options. Anything not matched by at least one rule is removed.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RetentionPolicy {
+    pub keep_last: Option<u32>,
+    pub keep_hourly: Option<u32>,
+    pub keep_daily: Option<u32>,
+    pub keep_weekly: Option<u32>,
+    pub keep_monthly: Option<u32>,
+    pub keep_yearly: Option<u32>,
+    /// A duration such as `1y5m7d2h`, relative to the latest snapshot.
+    pub keep_within: Option<String>,
+    #[serde(default)]
+    pub keep_tags: Vec<String>,
+}
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.to_args().is_empty()
+    }
+
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = vec![];
+
+        let counts = [
+            ("--keep-last", self.keep_last),
+            ("--keep-hourly", self.keep_hourly),
+            ("--keep-daily", self.keep_daily),
+            ("--keep-weekly", self.keep_weekly),
+            ("--keep-monthly", self.keep_monthly),
+            ("--keep-yearly", self.keep_yearly),
+        ];
+        for (option, count) in counts.iter() {
+            if let Some(count) = count {
+                args.push(option.to_string());
+                args.push(count.to_string());
             }
         }
+
+        if let Some(within) = &self.keep_within {
+            args.push("--keep-within".to_string());
+            args.push(within.clone());
+        }
+
+        for tag in &self.keep_tags {
+            args.push("--keep-tag".to_string());
+            args.push(tag.clone());
+        }
+
+        args
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The longest interval a backup can be scheduled to run at, a year.
+pub const MAX_SCHEDULE_INTERVAL: u64 = 366 * 24 * 60 * 60;
+
+/// Why a string could not be understood as a schedule.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ScheduleParseError {
+    #[error("Bad interval: {0}")]
+    BadCount(#[from] std::num::ParseIntError),
+    #[error("Intervals can be at most a year long")]
+    TooLong,
+}
+
+/// When a backup should run on its own. Calendar expressions look like
+/// `hourly :15`, `daily 02:00`, `weekly mon 03:30` or `monthly 1 04:00`, and
+/// are validated by the daemon.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Schedule {
+    Interval { seconds: u64 },
+    Calendar { expression: String },
+}
+
+impl FromStr for Schedule {
+    type Err = ScheduleParseError;
+
+    /// Parses `every <n><unit>`, with unit one of s, m, h or d, into an
+    /// interval; anything else is taken to be a calendar expression.
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let string = string.trim();
+        let interval = match string.strip_prefix("every ") {
+            Some(interval) => interval.trim(),
+            None => return Ok(Schedule::Calendar { expression: string.to_string() }),
+        };
+
+        let (count, multiplier) = match interval.chars().last() {
+            Some('s') => (&interval[..interval.len() - 1], 1),
+            Some('m') => (&interval[..interval.len() - 1], 60),
+            Some('h') => (&interval[..interval.len() - 1], 60 * 60),
+            Some('d') => (&interval[..interval.len() - 1], 24 * 60 * 60),
+            _ => (interval, 1),
+        };
+
+        let seconds = count.trim().parse::<u64>()?.checked_mul(multiplier)
+            .filter(|seconds| *seconds <= MAX_SCHEDULE_INTERVAL)
+            .ok_or(ScheduleParseError::TooLong)?;
+        Ok(Schedule::Interval { seconds })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Schedule::Interval { seconds } if seconds % (24 * 60 * 60) == 0 =>
+                write!(f, "every {}d", seconds / (24 * 60 * 60)),
+            Schedule::Interval { seconds } if seconds % (60 * 60) == 0 =>
+                write!(f, "every {}h", seconds / (60 * 60)),
+            Schedule::Interval { seconds } if seconds % 60 == 0 =>
+                write!(f, "every {}m", seconds / 60),
+            Schedule::Interval { seconds } => write!(f, "every {}s", seconds),
+            Schedule::Calendar { expression } => write!(f, "{}", expression),
+        }
+    }
+}
+
+/// A backup as it is created or updated, with the secrets needed to get
+/// into its repository. Only the daemon keeps these around.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Backup {
     pub name: String,
     pub repository: Repository,
-    pub key_id: Option<String>,
-    pub key_secret: Option<String>,
-    pub password: String,
+    #[serde(default)]
+    pub credentials: Credentials,
+    /// For servers with certificates of their own, like a REST server on
+    /// the local network, and those that want a client certificate.
+    #[serde(default)]
+    pub ca_cert: Option<PathBuf>,
+    #[serde(default)]
+    pub client_cert: Option<PathBuf>,
+    /// Only used by rclone.
+    #[serde(default)]
+    pub rclone: Option<RcloneOptions>,
+    /// Left empty when updating a backup to keep the current one, as are
+    /// the secrets in the credentials.
+    #[serde(default)]
+    pub password: Secret,
     pub include: Vec<PathBuf>,
     pub exclude: Vec<String>,
+    #[serde(default)]
+    pub retention: Option<RetentionPolicy>,
+    #[serde(default)]
+    pub schedule: Option<Schedule>,
+}
+
+impl Backup {
+    /// What may be shown to any client.
+    pub fn summary(&self) -> BackupSummary {
+        BackupSummary {
+            name: self.name.clone(),
+            repository: self.repository.clone(),
+            credentials: self.credentials.redacted(),
+            ca_cert: self.ca_cert.clone(),
+            client_cert: self.client_cert.clone(),
+            rclone: self.rclone.clone(),
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+            retention: self.retention.clone(),
+            schedule: self.schedule.clone(),
+        }
+    }
+
+    /// Takes the secrets `previous` had wherever this backup leaves them
+    /// empty, as long as it still reaches the same repository the same way.
+    pub fn keep_secrets(&mut self, previous: &Backup) {
+        if !self.same_repository(previous) {
+            return;
+        }
+
+        if self.password.is_empty() {
+            self.password = previous.password.clone();
+        }
+        self.credentials.keep_secrets(&previous.credentials);
+    }
+
+    /// Whether this backup leaves empty a password or secret `previous` has.
+    pub fn lacks_secrets_of(&self, previous: &Backup) -> bool {
+        (self.password.is_empty() && !previous.password.is_empty())
+            || self.credentials.lack_secrets_of(&previous.credentials)
+    }
+
+    fn same_repository(&self, previous: &Backup) -> bool {
+        self.repository == previous.repository
+            && self.ca_cert == previous.ca_cert
+            && self.client_cert == previous.client_cert
+    }
+}
+
+/// A backup as listed to clients: everything but its password, with the
+/// secrets in the credentials left empty.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupSummary {
+    pub name: String,
+    pub repository: Repository,
+    #[serde(default)]
+    pub credentials: Credentials,
+    #[serde(default)]
+    pub ca_cert: Option<PathBuf>,
+    #[serde(default)]
+    pub client_cert: Option<PathBuf>,
+    #[serde(default)]
+    pub rclone: Option<RcloneOptions>,
+    pub include: Vec<PathBuf>,
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub retention: Option<RetentionPolicy>,
+    #[serde(default)]
+    pub schedule: Option<Schedule>,
+}
+
+pub fn add_message_type(json_string: &str, type_string: &str) -> String {
+    add_key(json_string, "message_type", type_string.to_string())
+}
+
+pub fn add_key(json_string: &str, key: &str, value: String) -> String {
+    let mut object_value: serde_json::Value = serde_json::from_str(json_string).unwrap();
+    let object = object_value.as_object_mut().unwrap();
+    object.insert(
+        key.to_string(),
+        serde_json::Value::String(value)
+    );
+    object_value.to_string()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
+    use strum::IntoEnumIterator;
 
     #[test]
     fn it_works() {
@@ -103,17 +508,90 @@ mod tests {
                 identifier: "server-test".to_string(),
                 path: "/system".to_string(),
             },
-            key_id: Some("key-id".to_string()),
-            key_secret: Some("very-secret".to_string()),
-            password: "pass".to_string(),
+            credentials: Credentials::B2 {
+                account_id: "key-id".to_string(),
+                account_key: "very-secret".into(),
+            },
+            ca_cert: None,
+            client_cert: None,
+            rclone: None,
+            password: "pass".into(),
             include: vec![],
             exclude: vec![],
+            retention: None,
+            schedule: None,
         };
 
         assert_eq!(
             serde_json::to_string(&backup).unwrap(),
-            "{\"name\":\"test\",\"repository\":{\"kind\":\"b2\",\"identifier\":\"server-test\",\"path\":\"/system\"},\"key_id\":\"key-id\",\"key_secret\":\"very-secret\",\"password\":\"pass\",\"include\":[],\"exclude\":[]}".to_string()
-        )
+            "{\"name\":\"test\",\"repository\":{\"kind\":\"b2\",\"identifier\":\"server-test\",\"path\":\"/system\"},\"credentials\":{\"kind\":\"b2\",\"account_id\":\"key-id\",\"account_key\":\"very-secret\"},\"ca_cert\":null,\"client_cert\":null,\"rclone\":null,\"password\":\"pass\",\"include\":[],\"exclude\":[],\"retention\":null,\"schedule\":null}".to_string()
+        );
+
+        assert!(!format!("{:?}", backup).contains("\"pass\""));
+        assert!(!format!("{:?}", backup).contains("very-secret"));
+
+        let summary = serde_json::to_value(backup.summary()).unwrap();
+        assert!(summary.get("password").is_none());
+        assert_eq!(summary["credentials"]["account_key"], "");
+
+        let mut update = backup.clone();
+        update.password = Secret::default();
+        update.credentials = backup.credentials.redacted();
+        update.keep_secrets(&backup);
+        assert!(!update.lacks_secrets_of(&backup));
+        assert_eq!(update.password, backup.password);
+
+        let mut moved = backup.clone();
+        moved.repository.identifier = "elsewhere".to_string();
+        moved.password = Secret::default();
+        moved.credentials = backup.credentials.redacted();
+        moved.keep_secrets(&backup);
+        assert!(moved.lacks_secrets_of(&backup));
+        assert!(moved.password.is_empty());
+    }
+
+    #[test]
+    fn retention_policy_args() {
+        let policy = RetentionPolicy {
+            keep_daily: Some(7),
+            keep_weekly: Some(4),
+            keep_within: Some("1y".to_string()),
+            keep_tags: vec!["important".to_string()],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            policy.to_args(),
+            vec![
+                "--keep-daily", "7",
+                "--keep-weekly", "4",
+                "--keep-within", "1y",
+                "--keep-tag", "important",
+            ]
+        );
+
+        assert!(RetentionPolicy::default().is_empty());
+        assert!(!policy.is_empty());
+    }
+
+    #[test]
+    fn schedule_parsing() {
+        assert_eq!(
+            Schedule::from_str("every 6h").unwrap(),
+            Schedule::Interval { seconds: 6 * 60 * 60 }
+        );
+        assert_eq!(
+            Schedule::from_str(" daily 02:00 ").unwrap(),
+            Schedule::Calendar { expression: "daily 02:00".to_string() }
+        );
+        assert!(Schedule::from_str("every often").is_err());
+        assert_eq!(Schedule::from_str("every 366d").unwrap(), Schedule::Interval { seconds: MAX_SCHEDULE_INTERVAL });
+        assert_eq!(Schedule::from_str("every 367d"), Err(ScheduleParseError::TooLong));
+        assert_eq!(Schedule::from_str("every 18446744073709551615d"), Err(ScheduleParseError::TooLong));
+
+        for string in &["every 90s", "every 15m", "every 2d", "weekly mon 03:30"] {
+            assert_eq!(&Schedule::from_str(string).unwrap().to_string(), string);
+        }
     }
 
     #[test]
@@ -127,7 +605,7 @@ mod tests {
 
         assert_eq!(
             repo_reference,
-            Repository::from("b2:back:/blaze")
+            "b2:back:/blaze".parse().unwrap()
         );
 
         assert_eq!(
@@ -147,7 +625,7 @@ mod tests {
 
         assert_eq!(
             repo_reference,
-            Repository::from("/mnt/backup")
+            Repository::try_from("/mnt/backup").unwrap()
         );
 
         assert_eq!(
@@ -155,18 +633,83 @@ mod tests {
             repo_reference.to_string()
         );
      }
-}
 
-pub fn add_message_type(json_string: &str, type_string: &str) -> String {
-    add_key(json_string, "message_type", type_string.to_string())
-}
+    #[test]
+    fn repository_parsing() {
+        let repository: Repository = "sftp:user@[::1]:/srv/a:b".parse().unwrap();
+        assert_eq!(repository.identifier, "user@[::1]");
+        assert_eq!(repository.path, "/srv/a:b");
 
-pub fn add_key(json_string: &str, key: &str, value: String) -> String {
-    let mut object_value: serde_json::Value = serde_json::from_str(json_string).unwrap();
-    let object = object_value.as_object_mut().unwrap();
-    object.insert(
-        key.to_string(),
-        serde_json::Value::String(value)
-    );
-    object_value.to_string()
+        let repository: Repository = "s3:http://localhost:9000/backups/home".parse().unwrap();
+        assert_eq!(repository.identifier, "http://localhost:9000");
+        assert_eq!(repository.path, "backups/home");
+
+        let repository: Repository = "rest:https://nas:8000".parse().unwrap();
+        assert_eq!(repository.identifier, "https://nas:8000");
+        assert_eq!(repository.to_string(), "rest:https://nas:8000/");
+
+        let repository: Repository = "rclone:gdrive:backups/home".parse().unwrap();
+        assert_eq!(repository.identifier, "gdrive");
+        assert_eq!(repository.path, "backups/home");
+
+        let repository: Repository = "gs:bucket:/restic".parse().unwrap();
+        assert_eq!(repository.kind, RepositoryKind::GCS);
+        assert_eq!(repository.to_string(), "gs:bucket:/restic");
+
+        let repository: Repository = "local:backups".parse().unwrap();
+        assert_eq!(repository.kind, RepositoryKind::Local);
+        assert_eq!(repository.to_string(), "local:backups");
+
+        let errors = vec![
+            ("", RepositoryParseError::Empty),
+            ("backups", RepositoryParseError::Malformed("backups".to_string())),
+            ("ftp:host:/path", RepositoryParseError::UnknownKind("ftp".to_string())),
+            ("sftp::/path", RepositoryParseError::MissingIdentifier(RepositoryKind::SFTP)),
+            ("sftp:host", RepositoryParseError::MissingPath),
+            ("sftp:host:", RepositoryParseError::MissingPath),
+            ("local:", RepositoryParseError::MissingPath),
+            ("s3:s3.amazonaws.com", RepositoryParseError::MissingBucket),
+            ("s3:https://", RepositoryParseError::MissingIdentifier(RepositoryKind::S3)),
+            ("rest:nas:8000/", RepositoryParseError::MissingScheme("nas:8000".to_string())),
+            ("s3:/bucket", RepositoryParseError::MissingIdentifier(RepositoryKind::S3)),
+            ("/mnt/backup\n", RepositoryParseError::ControlCharacter),
+        ];
+        for (string, error) in errors {
+            assert_eq!(Repository::from_str(string), Err(error), "{:?}", string);
+        }
+
+        let repository = Repository {
+            kind: RepositoryKind::B2,
+            identifier: "a:b".to_string(),
+            path: "".to_string(),
+        };
+        assert_eq!(repository.validate(), Err(RepositoryParseError::BadIdentifier("a:b".to_string())));
+    }
+
+    fn any_repository() -> impl Strategy<Value = Repository> {
+        let kinds = RepositoryKind::iter().collect::<Vec<_>>();
+        let schemes = vec!["", "http://", "https://"];
+        (prop::sample::select(kinds), prop::sample::select(schemes),
+            "[^:\\[\\]\\p{Cc}]{0,16}", "[^\\p{Cc}]{0,32}")
+            .prop_map(|(kind, scheme, identifier, path)| match kind {
+                RepositoryKind::Local => Repository { kind, identifier: "".to_string(), path },
+                RepositoryKind::S3 | RepositoryKind::Rest => {
+                    Repository { kind, identifier: format!("{}{}", scheme, identifier), path }
+                },
+                _ => Repository { kind, identifier, path },
+            })
+            .prop_filter("must be valid", |repository| repository.validate().is_ok())
+    }
+
+    proptest! {
+        #[test]
+        fn repository_round_trip(repository in any_repository()) {
+            prop_assert_eq!(repository.to_string().parse::<Repository>(), Ok(repository));
+        }
+
+        #[test]
+        fn repository_parsing_does_not_panic(string in "\\PC*") {
+            let _ = Repository::from_str(&string);
+        }
+    }
 }
@@ -1,8 +1,12 @@
-use crate::{Backup, ServerError};
+use std::collections::HashMap;
+use crate::{BackupSummary, Credentials, Secret, ServerError};
 use serde::{Serialize, Deserialize};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ResticMessageStatus {
+    /// The backup this is about; added by duplikatd, restic does not know it.
+    #[serde(default)]
+    pub name: Option<String>,
     pub percent_done: f64,
     pub total_files: Option<u64>,
     pub files_done: Option<u64>,
@@ -12,8 +16,47 @@ pub struct ResticMessageStatus {
     pub seconds_remaining: Option<u64>,
 }
 
+/// Sent by restic for every file it looks at when run with --verbose.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResticMessageVerboseStatus {
+    /// The backup this is about; added by duplikatd, restic does not know it.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// What happened to the item: new, unchanged, modified or scan_finished.
+    pub action: String,
+    #[serde(default)]
+    pub item: String,
+    #[serde(default)]
+    pub duration: f64,
+    #[serde(default)]
+    pub data_size: u64,
+    #[serde(default)]
+    pub metadata_size: u64,
+    #[serde(default)]
+    pub total_files: u64,
+}
+
+/// Something restic could not back up, such as a file it was not allowed to
+/// read. The backup goes on without it, and ends up incomplete.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResticMessageError {
+    /// The backup this is about; added by duplikatd, restic does not know it.
+    #[serde(default)]
+    pub name: Option<String>,
+    pub message: String,
+    /// What restic was doing, such as scan or archival.
+    #[serde(default)]
+    pub during: String,
+    /// The file or directory affected, if any.
+    #[serde(default)]
+    pub item: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ResticMessageSummary {
+    /// The backup this is about; added by duplikatd, restic does not know it.
+    #[serde(default)]
+    pub name: Option<String>,
     pub files_new: u64,
     pub files_changed: u64,
     pub files_unmodified: u64,
@@ -29,9 +72,102 @@ pub struct ResticMessageSummary {
     pub snapshot_id: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResticMessageRestoreStatus {
+    pub percent_done: f64,
+    pub total_files: Option<u64>,
+    pub files_restored: Option<u64>,
+    pub files_skipped: Option<u64>,
+    pub total_bytes: Option<u64>,
+    pub bytes_restored: Option<u64>,
+    pub bytes_skipped: Option<u64>,
+    pub seconds_elapsed: Option<u64>,
+    pub seconds_remaining: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResticMessageRestoreSummary {
+    pub total_files: u64,
+    pub files_restored: u64,
+    pub files_skipped: Option<u64>,
+    pub total_bytes: u64,
+    pub bytes_restored: u64,
+    pub bytes_skipped: Option<u64>,
+    pub seconds_elapsed: u64,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ResticMessageBackupsList {
-    pub list: Vec<Backup>
+    pub list: Vec<BackupSummary>,
+    /// When each scheduled backup will run next, in RFC 3339 format.
+    #[serde(default)]
+    pub next_runs: HashMap<String, String>,
+    /// The most recent run of each backup that has been run before.
+    #[serde(default)]
+    pub last_runs: HashMap<String, RunRecord>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResticMessageSecret {
+    pub name: String,
+    pub password: Secret,
+    pub credentials: Credentials,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Snapshot {
+    pub id: String,
+    pub short_id: String,
+    pub time: String,
+    pub hostname: String,
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub username: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResticMessageSnapshotsList {
+    pub name: String,
+    pub list: Vec<Snapshot>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotNodeKind {
+    File,
+    Dir,
+    Symlink,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SnapshotNode {
+    pub name: String,
+    pub path: String,
+    #[serde(rename = "type")]
+    pub kind: SnapshotNodeKind,
+    #[serde(default)]
+    pub size: u64,
+    pub mtime: String,
+    pub mode: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResticMessageSnapshotTree {
+    pub name: String,
+    pub snapshot: String,
+    pub path: String,
+    pub entries: Vec<SnapshotNode>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResticMessageForgetSummary {
+    pub name: String,
+    pub keep: Vec<Snapshot>,
+    pub remove: Vec<Snapshot>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -41,18 +177,114 @@ pub struct ResticMessageBackupStats {
     pub total_file_count: u64,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResticMessageCancelled {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RunTrigger {
+    Manual,
+    Scheduled,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RunOutcome {
+    Succeeded,
+    /// A snapshot was made, but some files could not be read.
+    Partial,
+    Failed,
+    Cancelled,
+}
+
+/// A finished backup run, as kept in the daemon's run history. Times are
+/// RFC 3339 strings.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RunRecord {
+    pub started: String,
+    pub finished: String,
+    pub trigger: RunTrigger,
+    pub outcome: RunOutcome,
+    /// Counters and snapshot id, if restic got as far as reporting them.
+    pub summary: Option<ResticMessageSummary>,
+    #[serde(default)]
+    pub error: Option<ServerError>,
+    /// What restic could not back up, which may be truncated.
+    #[serde(default)]
+    pub errors: Vec<ResticMessageError>,
+    /// Whatever restic printed to stderr, which may be truncated.
+    #[serde(default)]
+    pub stderr: String,
+}
+
+/// Sent to everyone following a backup once it is done, whatever the outcome.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResticMessageRunFinished {
+    pub name: String,
+    pub run: RunRecord,
+}
+
+/// First message of an event subscription, listing the backups that are
+/// running at that point. Their latest status follows.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResticMessageSubscribed {
+    pub running: Vec<String>,
+}
+
+/// Most recent run first.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResticMessageRunHistory {
+    pub name: String,
+    pub runs: Vec<RunRecord>,
+}
+
+/// A line of a backup's log. The time is an RFC 3339 string, the level one
+/// of ERROR, WARN, INFO, DEBUG or TRACE.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    pub time: String,
+    pub level: String,
+    pub message: String,
+}
+
+/// Oldest entry first.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResticMessageLogs {
+    pub name: String,
+    pub entries: Vec<LogEntry>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "message_type", rename_all = "lowercase")]
 pub enum ResticMessage {
     Status(ResticMessageStatus),
+    #[serde(rename = "verbose_status")]
+    VerboseStatus(ResticMessageVerboseStatus),
+    Error(ResticMessageError),
     Summary(ResticMessageSummary),
     BackupsList(ResticMessageBackupsList),
     BackupStats(ResticMessageBackupStats),
+    Cancelled(ResticMessageCancelled),
+    RestoreStatus(ResticMessageRestoreStatus),
+    RestoreSummary(ResticMessageRestoreSummary),
+    SnapshotsList(ResticMessageSnapshotsList),
+    SnapshotTree(ResticMessageSnapshotTree),
+    ForgetSummary(ResticMessageForgetSummary),
+    RunHistory(ResticMessageRunHistory),
+    Logs(ResticMessageLogs),
+    RunFinished(ResticMessageRunFinished),
+    Subscribed(ResticMessageSubscribed),
+    Secret(ResticMessageSecret),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ServerResponse {
     pub error: Option<ServerError>,
+    /// Meant for people: OK, or what the error means.
+    #[serde(default)]
+    pub message: Option<String>,
 }
 
 #[cfg(test)]
@@ -71,6 +303,7 @@ mod tests {
            "bytes_done": 180231
        });
        let status_message = ResticMessage::Status(ResticMessageStatus {
+           name: None,
            percent_done: 0.07672352397538289,
            total_files: Some(5),
            files_done: Some(2),
@@ -87,4 +320,119 @@ mod tests {
             }
        }
     }
+
+    #[test]
+    fn restore_messages() {
+        let summary_message_value = json!({
+            "message_type": "restoresummary",
+            "seconds_elapsed": 3,
+            "total_files": 5,
+            "files_restored": 5,
+            "total_bytes": 2349097,
+            "bytes_restored": 2349097
+        });
+        let deserialized_message: ResticMessage = serde_json::from_value(summary_message_value).unwrap();
+        if let ResticMessage::RestoreSummary(summary) = deserialized_message {
+            assert_eq!(summary.files_restored, 5);
+            assert_eq!(summary.bytes_restored, 2349097);
+            assert_eq!(summary.files_skipped, None);
+        } else {
+            panic!("Expected a restore summary");
+        }
+    }
+
+    #[test]
+    fn restic_snapshots() {
+        // As printed by `restic snapshots --json`.
+        let restic_output = json!([{
+            "time": "2021-07-18T10:02:45.316528+02:00",
+            "tree": "4f0b1c3ec1e6d3e0c6b6e4bb2b1f7cb1a2eb9d2b8c6e52b7e5a3c4b4b1d6b0f6",
+            "paths": ["/home/kov"],
+            "hostname": "nyx",
+            "username": "kov",
+            "uid": 1000,
+            "gid": 1000,
+            "id": "a3b2c1d0e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0d1e2f3a4b5c6d7e8f9a0b1",
+            "short_id": "a3b2c1d0"
+        }]);
+        let snapshots: Vec<Snapshot> = serde_json::from_value(restic_output).unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].short_id, "a3b2c1d0");
+        assert_eq!(snapshots[0].paths, vec!["/home/kov".to_string()]);
+        assert!(snapshots[0].tags.is_empty());
+    }
+
+    #[test]
+    fn restic_ls_node() {
+        // As printed by `restic ls --json`, one per line.
+        let restic_output = json!({
+            "name": "fifo",
+            "type": "fifo",
+            "path": "/home/kov/fifo",
+            "uid": 1000,
+            "gid": 1000,
+            "mode": 2147484068u32,
+            "mtime": "2021-07-18T10:02:45.316528+02:00",
+            "atime": "2021-07-18T10:02:45.316528+02:00",
+            "ctime": "2021-07-18T10:02:45.316528+02:00",
+            "struct_type": "node"
+        });
+        let node: SnapshotNode = serde_json::from_value(restic_output).unwrap();
+        assert_eq!(node.kind, SnapshotNodeKind::Other);
+        assert_eq!(node.size, 0);
+    }
+
+    #[test]
+    fn run_record() {
+        let record_value = json!({
+            "started": "2021-07-18T02:00:00+02:00",
+            "finished": "2021-07-18T02:03:10+02:00",
+            "trigger": "scheduled",
+            "outcome": "cancelled",
+            "summary": null
+        });
+        let record: RunRecord = serde_json::from_value(record_value).unwrap();
+        assert_eq!(record.trigger, RunTrigger::Scheduled);
+        assert_eq!(record.outcome, RunOutcome::Cancelled);
+        assert!(record.summary.is_none());
+        assert!(record.error.is_none());
+        assert!(record.stderr.is_empty());
+
+        let record_value = json!({
+            "started": "2021-07-18T02:00:00+02:00",
+            "finished": "2021-07-18T02:03:10+02:00",
+            "trigger": "manual",
+            "outcome": "failed",
+            "summary": null,
+            "error": {"code": "wrong_password", "detail": "Fatal: wrong password or no key found"}
+        });
+        let record: RunRecord = serde_json::from_value(record_value).unwrap();
+        assert!(matches!(record.error, Some(ServerError::WrongPassword { .. })));
+    }
+
+    #[test]
+    fn restic_error_messages() {
+        let message: ResticMessage = serde_json::from_value(json!({
+            "message_type": "error",
+            "name": "home",
+            "message": "open /home/kov/secret: permission denied",
+            "during": "archival",
+            "item": "/home/kov/secret"
+        })).unwrap();
+        match message {
+            ResticMessage::Error(error) => assert_eq!(error.item, "/home/kov/secret"),
+            _ => panic!("Expected an error message"),
+        }
+
+        let message: ResticMessage = serde_json::from_value(json!({
+            "message_type": "verbose_status",
+            "action": "new",
+            "item": "/home/kov/notes.txt",
+            "duration": 0.001,
+            "data_size": 1200,
+            "metadata_size": 0,
+            "total_files": 1
+        })).unwrap();
+        assert!(matches!(message, ResticMessage::VerboseStatus(_)));
+    }
 }
@@ -0,0 +1,432 @@
+use std::{cell::RefCell, collections::BTreeSet, rc::Rc};
+use duplikat_types::*;
+use glib::{MainContext, clone};
+use gtk::prelude::*;
+use crate::Application;
+use crate::server::Server;
+use crate::utils::show_error;
+
+pub struct SnapshotBrowserUI {
+    pub container: gtk::Box,
+    myself: Option<Rc<RefCell<Self>>>,
+    application: Rc<RefCell<Application>>,
+    name: String,
+    path: String,
+    selected: BTreeSet<String>,
+    title: gtk::Label,
+    snapshots: gtk::ComboBoxText,
+    path_label: gtk::Label,
+    up_button: gtk::Button,
+    entries: gtk::ListBox,
+    restore_button: gtk::Button,
+    progress_bar: gtk::ProgressBar,
+}
+
+fn parent_path(path: &str) -> String {
+    match path.trim_end_matches('/').rsplit_once('/') {
+        Some(("", _)) | None => "/".to_string(),
+        Some((parent, _)) => parent.to_string(),
+    }
+}
+
+impl SnapshotBrowserUI {
+    pub(crate) fn new(application: Rc<RefCell<Application>>) -> Rc<RefCell<Self>> {
+        let container = gtk::Box::new(gtk::Orientation::Vertical, 16);
+        container.set_widget_name("snapshot_browser");
+
+        // Top bar, with a way back to the overview and snapshot selection.
+        let top = gtk::Box::new(gtk::Orientation::Horizontal, 16);
+        container.append(&top);
+
+        let back_button = gtk::Button::from_icon_name(Some("go-previous-symbolic"));
+        top.append(&back_button);
+
+        let title = gtk::Label::new(None);
+        title.set_halign(gtk::Align::Start);
+        top.append(&title);
+
+        let snapshots = gtk::ComboBoxText::new();
+        snapshots.set_halign(gtk::Align::End);
+        snapshots.set_hexpand(true);
+        top.append(&snapshots);
+
+        // Current location.
+        let location = gtk::Box::new(gtk::Orientation::Horizontal, 16);
+        container.append(&location);
+
+        let up_button = gtk::Button::from_icon_name(Some("go-up-symbolic"));
+        up_button.set_sensitive(false);
+        location.append(&up_button);
+
+        let path_label = gtk::Label::new(Some("/"));
+        path_label.set_halign(gtk::Align::Start);
+        location.append(&path_label);
+
+        // Directory contents.
+        let scrolled = gtk::ScrolledWindow::new();
+        scrolled.set_policy(gtk::PolicyType::Never, gtk::PolicyType::Automatic);
+        scrolled.set_vexpand(true);
+        container.append(&scrolled);
+
+        let entries = gtk::ListBox::new();
+        entries.set_widget_name("snapshot_entries");
+        entries.set_selection_mode(gtk::SelectionMode::None);
+        entries.set_show_separators(true);
+        entries.set_css_classes(&["rich-list"]);
+        scrolled.set_child(Some(&entries));
+
+        // Restore.
+        let bottom = gtk::Box::new(gtk::Orientation::Horizontal, 16);
+        container.append(&bottom);
+
+        let progress_bar = gtk::ProgressBar::new();
+        progress_bar.set_show_text(true);
+        progress_bar.set_hexpand(true);
+        progress_bar.set_valign(gtk::Align::Center);
+        progress_bar.set_visible(false);
+        bottom.append(&progress_bar);
+
+        let restore_button = gtk::Button::with_label("Restore selected");
+        restore_button.set_halign(gtk::Align::End);
+        restore_button.set_hexpand(true);
+        restore_button.set_css_classes(&["suggested-action"]);
+        restore_button.set_sensitive(false);
+        bottom.append(&restore_button);
+
+        let myself = Rc::new(RefCell::new(
+            SnapshotBrowserUI {
+                container: container.clone(),
+                myself: None,
+                application: application.clone(),
+                name: String::new(),
+                path: "/".to_string(),
+                selected: Default::default(),
+                title,
+                snapshots: snapshots.clone(),
+                path_label,
+                up_button: up_button.clone(),
+                entries: entries.clone(),
+                restore_button: restore_button.clone(),
+                progress_bar,
+            }
+        ));
+
+        // Same trick used by the create/edit UI, so methods can hand out new
+        // clones of the Rc to signal handlers.
+        myself.borrow_mut().myself.replace(myself.clone());
+
+        back_button.connect_clicked(move |_| {
+            application.borrow().stack.set_visible_child_name("overview");
+        });
+
+        let changed_self = myself.clone();
+        snapshots.connect_changed(move |_| {
+            // Filling the combo box while we are borrowed also ends up here, the
+            // caller takes care of loading the tree in that case.
+            if let Ok(browser) = changed_self.try_borrow() {
+                browser.load("/");
+            }
+        });
+
+        let up_self = myself.clone();
+        up_button.connect_clicked(move |_| {
+            let browser = up_self.borrow();
+            browser.load(&parent_path(&browser.path));
+        });
+
+        let activated_self = myself.clone();
+        entries.connect_row_activated(move |_, row| {
+            let path = row.widget_name().to_string();
+            activated_self.borrow().load(&path);
+        });
+
+        let restore_self = myself.clone();
+        restore_button.connect_clicked(move |_| {
+            restore_self.borrow().restore();
+        });
+
+        myself
+    }
+
+    pub fn open(&mut self, name: &str) {
+        self.name = name.to_string();
+        self.selected.clear();
+        self.title.set_markup(&format!("<b>{}</b>", glib::markup_escape_text(name)));
+        self.restore_button.set_sensitive(false);
+        self.progress_bar.set_visible(false);
+        self.clear_entries();
+
+        self.application.borrow().stack.set_visible_child_name("browser");
+
+        let myself = self.clone_self();
+        let application = self.application.clone();
+        let name = self.name.clone();
+        MainContext::default().spawn_local(async move {
+            let connection = match Server::connect(application.clone()).await {
+                Ok(c) => c,
+                Err(_) => return,
+            };
+
+            if let Err(error) = connection.send_message(
+                ClientMessage::ListSnapshots(ClientMessageListSnapshots { name })
+            ).await {
+                println!("Error listing snapshots: {:#?}", error);
+                return;
+            };
+
+            let snapshots = match connection.read_message_or_error().await {
+                Ok(Some(Ok(ResticMessage::SnapshotsList(snapshots)))) => snapshots,
+                Ok(Some(Err(error))) => {
+                    let parent_window = application.borrow().main_window.clone();
+                    show_error(&parent_window, "Failed to list snapshots.", &error).await;
+                    return;
+                },
+                _ => return,
+            };
+
+            let browser = myself.borrow_mut();
+            browser.snapshots.remove_all();
+
+            // Most recent first, as that is what people usually want to restore.
+            for snapshot in snapshots.list.iter().rev() {
+                browser.snapshots.append(
+                    Some(&snapshot.id),
+                    &format!("{} ({})", snapshot.time, snapshot.short_id)
+                );
+            }
+
+            if let Some(latest) = snapshots.list.last() {
+                browser.snapshots.set_active_id(Some(&latest.id));
+                browser.load("/");
+            }
+        });
+    }
+
+    fn load(&self, path: &str) {
+        let snapshot = match self.snapshots.active_id() {
+            Some(snapshot) => snapshot.to_string(),
+            None => return,
+        };
+
+        let myself = self.clone_self();
+        let application = self.application.clone();
+        let name = self.name.clone();
+        let path = path.to_string();
+        MainContext::default().spawn_local(async move {
+            let connection = match Server::connect(application.clone()).await {
+                Ok(c) => c,
+                Err(_) => return,
+            };
+
+            if let Err(error) = connection.send_message(
+                ClientMessage::ListSnapshotTree(ClientMessageListSnapshotTree {
+                    name,
+                    snapshot,
+                    path,
+                })
+            ).await {
+                println!("Error listing snapshot contents: {:#?}", error);
+                return;
+            };
+
+            match connection.read_message_or_error().await {
+                Ok(Some(Ok(ResticMessage::SnapshotTree(mut tree)))) => {
+                    // Directories first, then files, alphabetically.
+                    tree.entries.sort_by(|a, b| {
+                        (a.kind != SnapshotNodeKind::Dir, &a.name)
+                            .cmp(&(b.kind != SnapshotNodeKind::Dir, &b.name))
+                    });
+
+                    myself.borrow_mut().show_tree(&tree);
+                },
+                Ok(Some(Err(error))) => {
+                    let parent_window = application.borrow().main_window.clone();
+                    show_error(&parent_window, "Failed to list the contents of the snapshot.", &error).await;
+                },
+                _ => (),
+            }
+        });
+    }
+
+    fn show_tree(&mut self, tree: &ResticMessageSnapshotTree) {
+        self.path = tree.path.clone();
+        self.path_label.set_text(&tree.path);
+        self.up_button.set_sensitive(tree.path != "/");
+        self.clear_entries();
+
+        for entry in &tree.entries {
+            let row = self.new_entry_row(entry);
+            self.entries.append(&row);
+        }
+    }
+
+    fn new_entry_row(&self, entry: &SnapshotNode) -> gtk::ListBoxRow {
+        let is_dir = entry.kind == SnapshotNodeKind::Dir;
+
+        // The row name carries the path, so activating a directory row can
+        // tell us where to go.
+        let row = gtk::ListBoxRowBuilder::new()
+            .activatable(is_dir)
+            .selectable(false)
+            .name(&entry.path)
+            .build();
+
+        let hbox = gtk::Box::new(gtk::Orientation::Horizontal, 16);
+        row.set_child(Some(&hbox));
+
+        let check = gtk::CheckButton::new();
+        check.set_active(self.selected.contains(&entry.path));
+        hbox.append(&check);
+
+        let icon_name = if is_dir { "folder-symbolic" } else { "text-x-generic-symbolic" };
+        hbox.append(&gtk::Image::from_icon_name(Some(icon_name)));
+
+        let label = gtk::Label::new(Some(&entry.name));
+        label.set_halign(gtk::Align::Start);
+        label.set_hexpand(true);
+        hbox.append(&label);
+
+        let details = gtk::Label::new(Some(&entry.mtime));
+        details.set_halign(gtk::Align::End);
+        hbox.append(&details);
+
+        let myself = self.clone_self();
+        let path = entry.path.clone();
+        check.connect_toggled(move |check| {
+            let mut browser = myself.borrow_mut();
+            if check.is_active() {
+                browser.selected.insert(path.clone());
+            } else {
+                browser.selected.remove(&path);
+            }
+            browser.restore_button.set_sensitive(!browser.selected.is_empty());
+        });
+
+        row
+    }
+
+    fn restore(&self) {
+        let snapshot = match self.snapshots.active_id() {
+            Some(snapshot) => snapshot.to_string(),
+            None => return,
+        };
+
+        let parent_window = self.application.borrow().main_window.clone();
+        let file_picker = gtk::FileChooserDialog::new(
+            Some("Choose where to restore to..."),
+            Some(&parent_window),
+            gtk::FileChooserAction::SelectFolder,
+            &[
+                ("Cancel", gtk::ResponseType::Cancel),
+                ("Restore", gtk::ResponseType::Accept),
+            ]
+        );
+        file_picker.set_modal(true);
+
+        let myself = self.clone_self();
+        file_picker.run_async(move |dialog, response| {
+            dialog.close();
+
+            if response != gtk::ResponseType::Accept {
+                return;
+            }
+
+            // Locations like those of GVFS without FUSE have no path the
+            // daemon could write to.
+            let target = match dialog.file().and_then(|file| file.path()) {
+                Some(target) => target,
+                None => {
+                    let message = gtk::MessageDialogBuilder::new()
+                        .transient_for(&parent_window)
+                        .modal(true)
+                        .message_type(gtk::MessageType::Error)
+                        .buttons(gtk::ButtonsType::Close)
+                        .text("Failed to restore.")
+                        .secondary_text("The chosen folder is not on this computer's file system.")
+                        .build();
+                    message.run_async(|message, _| message.close());
+                    return;
+                },
+            };
+            let (application, restore_button, progress_bar, restore) = {
+                let browser = myself.borrow();
+                let restore = ClientMessageRestoreSnapshot {
+                    name: browser.name.clone(),
+                    snapshot,
+                    target,
+                    include: browser.selected.iter().cloned().collect(),
+                    exclude: vec![],
+                };
+                (
+                    browser.application.clone(),
+                    browser.restore_button.clone(),
+                    browser.progress_bar.clone(),
+                    restore,
+                )
+            };
+
+            restore_button.set_sensitive(false);
+            progress_bar.set_fraction(0.);
+            progress_bar.set_text(None);
+            progress_bar.set_visible(true);
+
+            MainContext::default().spawn_local(
+                clone!(@weak restore_button, @weak progress_bar => async move {
+                    let connection = match Server::connect(application.clone()).await {
+                        Ok(c) => c,
+                        Err(_) => {
+                            restore_button.set_sensitive(true);
+                            return;
+                        },
+                    };
+
+                    if let Err(error) = connection.send_message(
+                        ClientMessage::RestoreSnapshot(restore)
+                    ).await {
+                        println!("Failed to restore...: {:#?}", error);
+                        restore_button.set_sensitive(true);
+                        return;
+                    };
+
+                    while let Ok(Some(message)) = connection.read_message_or_error().await {
+                        match message {
+                            Ok(ResticMessage::RestoreStatus(status)) => {
+                                progress_bar.set_fraction(status.percent_done);
+                            },
+                            Ok(ResticMessage::RestoreSummary(summary)) => {
+                                progress_bar.set_fraction(1.);
+                                progress_bar.set_text(Some(
+                                    &format!("Restored {} of {} files",
+                                        summary.files_restored,
+                                        summary.total_files,
+                                    )
+                                ));
+                                break;
+                            },
+                            Ok(_) => (),
+                            Err(error) => {
+                                progress_bar.set_visible(false);
+                                let parent_window = application.borrow().main_window.clone();
+                                show_error(&parent_window, "Failed to restore.", &error).await;
+                                break;
+                            },
+                        }
+                    }
+
+                    // Whatever happened, another go should be possible.
+                    restore_button.set_sensitive(true);
+                })
+            );
+        });
+    }
+
+    fn clear_entries(&self) {
+        while let Some(row) = self.entries.row_at_index(0) {
+            self.entries.remove(&row);
+        }
+    }
+
+    fn clone_self(&self) -> Rc<RefCell<Self>> {
+        self.myself.as_ref().unwrap().clone()
+    }
+}
@@ -7,7 +7,7 @@ use duplikat_types::*;
 use strum::IntoEnumIterator;
 use crate::Application;
 use crate::server::Server;
-use crate::utils::next_row_num;
+use crate::utils::{next_row_num, show_error};
 
 pub struct CreateEditUI {
     pub window: gtk::Dialog,
@@ -17,11 +17,26 @@ pub struct CreateEditUI {
     identifier: gtk::Entry,
     key_id: gtk::Entry,
     key_secret: gtk::Entry,
+    region: gtk::Entry,
+    auth_url: gtk::Entry,
+    project: gtk::Entry,
+    user_domain: gtk::Entry,
+    project_domain: gtk::Entry,
+    ca_cert: gtk::Entry,
+    client_cert: gtk::Entry,
+    rclone_config: gtk::Entry,
     path: gtk::Entry,
     password: gtk::PasswordEntry,
     confirm: gtk::PasswordEntry,
+    schedule: gtk::Entry,
     include: Vec<gtk::Editable>,
     exclude: Vec<gtk::Editable>,
+    include_list: gtk::ListBox,
+    exclude_list: gtk::ListBox,
+    feedback: gtk::Label,
+    // The backup as it was before we started editing it, if we are not
+    // creating a new one.
+    editing: Option<BackupSummary>,
     stack: gtk::Stack,
     back_button: gtk::Button,
     forward_button: gtk::Button,
@@ -33,6 +48,45 @@ enum Go {
     Forward,
 }
 
+fn default_include_path() -> PathBuf {
+    // FIXME: this is wrong, we need to ask the server what UID it is
+    // running with...
+    match users::get_effective_uid() {
+        0 => PathBuf::from("/"),
+        _ => dirs::home_dir().unwrap(),
+    }
+}
+
+fn default_exclude_patterns() -> Vec<String> {
+    let mut patterns = Vec::<String>::new();
+    if cfg!(target_os = "macos") {
+        let home_dir = dirs::home_dir()
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+
+        // FIXME: we need to ask the server if it running in privileged mode to
+        // filter which patterns to use as default.
+        [
+            ".cache",
+            "Caches",
+            &format!("{}/.Trash", home_dir),
+            &format!("{}/Library", home_dir),
+            "/private/var/folders",
+            "/private/var/networkd/db",
+            "/private/var/protected/trustd/private",
+            "/private/var/db"
+        ].iter().for_each(|p| patterns.push(p.to_string()));
+    }
+
+    if cfg!(target_os = "linux") {
+        patterns.push(".cache".to_string());
+        patterns.push("/var/lib/systemd/coredump".to_string());
+    }
+
+    patterns
+}
+
 impl CreateEditUI {
     pub(crate) fn new(application: Rc<RefCell<Application>>) -> Rc<RefCell<Self>> {
         let window = gtk::DialogBuilder::new()
@@ -104,11 +158,119 @@ impl CreateEditUI {
 
         grid.attach_next_to(&secret_entry, Some(&secret_label), gtk::PositionType::Right, 1, 1);
 
+        // Region
+        let region_label = gtk::Label::new(Some("Region"));
+        region_label.set_visible(false);
+
+        grid.attach(&region_label, 0, next_row_num(&mut row_num), 1, 1);
+
+        let region_entry = gtk::Entry::new();
+        region_entry.set_visible(false);
+        region_entry.set_placeholder_text(Some("us-east-1"));
+
+        grid.attach_next_to(&region_entry, Some(&region_label), gtk::PositionType::Right, 1, 1);
+
+        // Keystone authentication, for Swift
+        let auth_url_label = gtk::Label::new(Some("Auth URL"));
+        auth_url_label.set_visible(false);
+
+        grid.attach(&auth_url_label, 0, next_row_num(&mut row_num), 1, 1);
+
+        let auth_url_entry = gtk::Entry::new();
+        auth_url_entry.set_visible(false);
+        auth_url_entry.set_placeholder_text(Some("https://keystone.example.com:5000/v3"));
+
+        grid.attach_next_to(&auth_url_entry, Some(&auth_url_label), gtk::PositionType::Right, 1, 1);
+
+        let project_label = gtk::Label::new(Some("Project"));
+        project_label.set_visible(false);
+
+        grid.attach(&project_label, 0, next_row_num(&mut row_num), 1, 1);
+
+        let project_entry = gtk::Entry::new();
+        project_entry.set_visible(false);
+
+        grid.attach_next_to(&project_entry, Some(&project_label), gtk::PositionType::Right, 1, 1);
+
+        let user_domain_label = gtk::Label::new(Some("User Domain"));
+        user_domain_label.set_visible(false);
+
+        grid.attach(&user_domain_label, 0, next_row_num(&mut row_num), 1, 1);
+
+        let user_domain_entry = gtk::Entry::new();
+        user_domain_entry.set_visible(false);
+        user_domain_entry.set_placeholder_text(Some("Default"));
+
+        grid.attach_next_to(&user_domain_entry, Some(&user_domain_label), gtk::PositionType::Right, 1, 1);
+
+        let project_domain_label = gtk::Label::new(Some("Project Domain"));
+        project_domain_label.set_visible(false);
+
+        grid.attach(&project_domain_label, 0, next_row_num(&mut row_num), 1, 1);
+
+        let project_domain_entry = gtk::Entry::new();
+        project_domain_entry.set_visible(false);
+        project_domain_entry.set_placeholder_text(Some("Default"));
+
+        grid.attach_next_to(&project_domain_entry, Some(&project_domain_label), gtk::PositionType::Right, 1, 1);
+
+        // TLS certificates
+        let ca_cert_label = gtk::Label::new(Some("CA Certificate"));
+        ca_cert_label.set_visible(false);
+
+        grid.attach(&ca_cert_label, 0, next_row_num(&mut row_num), 1, 1);
+
+        let ca_cert_entry = gtk::Entry::new();
+        ca_cert_entry.set_visible(false);
+        ca_cert_entry.set_placeholder_text(Some("/path/to/ca.pem (optional)"));
+
+        grid.attach_next_to(&ca_cert_entry, Some(&ca_cert_label), gtk::PositionType::Right, 1, 1);
+
+        let client_cert_label = gtk::Label::new(Some("Client Certificate"));
+        client_cert_label.set_visible(false);
+
+        grid.attach(&client_cert_label, 0, next_row_num(&mut row_num), 1, 1);
+
+        let client_cert_entry = gtk::Entry::new();
+        client_cert_entry.set_visible(false);
+        client_cert_entry.set_placeholder_text(Some("/path/to/client.pem (optional)"));
+
+        grid.attach_next_to(&client_cert_entry, Some(&client_cert_label), gtk::PositionType::Right, 1, 1);
+
+        // rclone options
+        let rclone_config_label = gtk::Label::new(Some("rclone Config"));
+        rclone_config_label.set_visible(false);
+
+        grid.attach(&rclone_config_label, 0, next_row_num(&mut row_num), 1, 1);
+
+        let rclone_config_entry = gtk::Entry::new();
+        rclone_config_entry.set_visible(false);
+        rclone_config_entry.set_placeholder_text(Some("/path/to/rclone.conf (optional)"));
+
+        grid.attach_next_to(&rclone_config_entry, Some(&rclone_config_label), gtk::PositionType::Right, 1, 1);
+
+        // Path
+        let path_label = gtk::Label::new(Some("Path"));
+        grid.attach(&path_label, 0, next_row_num(&mut row_num), 1, 1);
+
+        let path = gtk::Entry::new();
+
+        grid.attach_next_to(&path, Some(&path_label), gtk::PositionType::Right, 1, 1);
+
         // Adjust entries based on type
         type_combo.connect_changed(
             clone!(@weak type_combo, @weak label, @weak identifier,
                    @weak key_label, @weak key_entry,
-                   @weak secret_label, @weak secret_entry => move |_| {
+                   @weak secret_label, @weak secret_entry,
+                   @weak region_label, @weak region_entry,
+                   @weak auth_url_label, @weak auth_url_entry,
+                   @weak project_label, @weak project_entry,
+                   @weak user_domain_label, @weak user_domain_entry,
+                   @weak project_domain_label, @weak project_domain_entry,
+                   @weak ca_cert_label, @weak ca_cert_entry,
+                   @weak client_cert_label, @weak client_cert_entry,
+                   @weak rclone_config_label, @weak rclone_config_entry,
+                   @weak path_label, @weak path => move |_| {
                 let repo_type = type_combo.active_id()
                     .expect("Combo box should never be empty")
                     .to_string();
@@ -124,6 +286,33 @@ impl CreateEditUI {
                 secret_label.set_visible(false);
                 secret_entry.set_visible(false);
 
+                region_label.set_visible(false);
+                region_entry.set_visible(false);
+
+                auth_url_label.set_visible(false);
+                auth_url_entry.set_visible(false);
+
+                project_label.set_visible(false);
+                project_entry.set_visible(false);
+
+                user_domain_label.set_visible(false);
+                user_domain_entry.set_visible(false);
+
+                project_domain_label.set_visible(false);
+                project_domain_entry.set_visible(false);
+
+                ca_cert_label.set_visible(false);
+                ca_cert_entry.set_visible(false);
+
+                client_cert_label.set_visible(false);
+                client_cert_entry.set_visible(false);
+
+                rclone_config_label.set_visible(false);
+                rclone_config_entry.set_visible(false);
+
+                path_label.set_label("Path");
+                path.set_placeholder_text(None);
+
                 match RepositoryKind::from_str(repo_type.as_str()).unwrap() {
                     RepositoryKind::Local => {
                         label.set_visible(false);
@@ -133,14 +322,124 @@ impl CreateEditUI {
                         label.set_label("Bucket");
                         identifier.set_placeholder_text(Some("bucket-name"));
 
+                        key_label.set_label("Key ID");
                         key_label.set_visible(true);
                         key_entry.set_visible(true);
                         key_entry.set_placeholder_text(Some("key-id"));
 
+                        secret_label.set_label("Secret ID");
                         secret_label.set_visible(true);
                         secret_entry.set_visible(true);
                         secret_entry.set_placeholder_text(Some("secret-id"));
                     },
+                    RepositoryKind::S3 => {
+                        label.set_label("Endpoint");
+                        identifier.set_placeholder_text(Some("s3.amazonaws.com"));
+
+                        key_label.set_label("Access Key");
+                        key_label.set_visible(true);
+                        key_entry.set_visible(true);
+                        key_entry.set_placeholder_text(Some("access-key-id"));
+
+                        secret_label.set_label("Secret Key");
+                        secret_label.set_visible(true);
+                        secret_entry.set_visible(true);
+                        secret_entry.set_placeholder_text(Some("secret-access-key"));
+
+                        region_label.set_visible(true);
+                        region_entry.set_visible(true);
+
+                        path_label.set_label("Bucket");
+                        path.set_placeholder_text(Some("bucket-name[/prefix]"));
+                    },
+                    RepositoryKind::Rest => {
+                        label.set_label("URL");
+                        identifier.set_placeholder_text(Some("https://host:8000"));
+
+                        key_label.set_label("Username");
+                        key_label.set_visible(true);
+                        key_entry.set_visible(true);
+                        key_entry.set_placeholder_text(Some("optional"));
+
+                        secret_label.set_label("Password");
+                        secret_label.set_visible(true);
+                        secret_entry.set_visible(true);
+                        secret_entry.set_placeholder_text(Some("optional"));
+
+                        ca_cert_label.set_visible(true);
+                        ca_cert_entry.set_visible(true);
+
+                        client_cert_label.set_visible(true);
+                        client_cert_entry.set_visible(true);
+
+                        path.set_placeholder_text(Some("optional"));
+                    },
+                    RepositoryKind::Azure => {
+                        label.set_label("Container");
+                        identifier.set_placeholder_text(Some("container-name"));
+
+                        key_label.set_label("Account Name");
+                        key_label.set_visible(true);
+                        key_entry.set_visible(true);
+                        key_entry.set_placeholder_text(Some("account-name"));
+
+                        secret_label.set_label("Account Key");
+                        secret_label.set_visible(true);
+                        secret_entry.set_visible(true);
+                        secret_entry.set_placeholder_text(Some("account-key"));
+                    },
+                    RepositoryKind::GCS => {
+                        label.set_label("Bucket");
+                        identifier.set_placeholder_text(Some("bucket-name"));
+
+                        key_label.set_label("Project ID");
+                        key_label.set_visible(true);
+                        key_entry.set_visible(true);
+                        key_entry.set_placeholder_text(Some("project-id"));
+
+                        secret_label.set_label("Credentials File");
+                        secret_label.set_visible(true);
+                        secret_entry.set_visible(true);
+                        secret_entry.set_placeholder_text(Some("/path/to/service-account.json"));
+                    },
+                    RepositoryKind::Swift => {
+                        label.set_label("Container");
+                        identifier.set_placeholder_text(Some("container-name"));
+
+                        auth_url_label.set_visible(true);
+                        auth_url_entry.set_visible(true);
+
+                        key_label.set_label("Username");
+                        key_label.set_visible(true);
+                        key_entry.set_visible(true);
+                        key_entry.set_placeholder_text(Some("username"));
+
+                        secret_label.set_label("Password");
+                        secret_label.set_visible(true);
+                        secret_entry.set_visible(true);
+                        secret_entry.set_placeholder_text(Some("password"));
+
+                        region_label.set_visible(true);
+                        region_entry.set_visible(true);
+
+                        project_label.set_visible(true);
+                        project_entry.set_visible(true);
+
+                        user_domain_label.set_visible(true);
+                        user_domain_entry.set_visible(true);
+
+                        project_domain_label.set_visible(true);
+                        project_domain_entry.set_visible(true);
+                    },
+                    RepositoryKind::Rclone => {
+                        label.set_label("Remote");
+                        identifier.set_placeholder_text(Some("gdrive"));
+
+                        rclone_config_label.set_visible(true);
+                        rclone_config_entry.set_visible(true);
+
+                        path.set_placeholder_text(Some("optional"));
+                    },
                     RepositoryKind::SFTP => {
                         label.set_label("Host");
                         identifier.set_placeholder_text(Some("[user@]host-or-ip.com"));
@@ -149,14 +448,6 @@ impl CreateEditUI {
             })
         );
 
-        // Path
-        let label = gtk::Label::new(Some("Path"));
-        grid.attach(&label, 0, next_row_num(&mut row_num), 1, 1);
-
-        let path = gtk::Entry::new();
-
-        grid.attach_next_to(&path, Some(&label), gtk::PositionType::Right, 1, 1);
-
         // Password
         let label = gtk::Label::new(Some("Password"));
         grid.attach(&label, 0, next_row_num(&mut row_num), 1, 1);
@@ -175,6 +466,15 @@ impl CreateEditUI {
 
         grid.attach_next_to(&confirm, Some(&label), gtk::PositionType::Right, 1, 1);
 
+        // Schedule
+        let label = gtk::Label::new(Some("Schedule"));
+        grid.attach(&label, 0, next_row_num(&mut row_num), 1, 1);
+
+        let schedule = gtk::Entry::new();
+        schedule.set_placeholder_text(Some("daily 02:00, every 6h, or empty to run manually"));
+
+        grid.attach_next_to(&schedule, Some(&label), gtk::PositionType::Right, 1, 1);
+
         // Add buttons, and signal handlers.
         let headerbar = window.header_bar();
 
@@ -193,31 +493,6 @@ impl CreateEditUI {
         add_backup.set_sensitive(false);
         headerbar.pack_end(&add_backup);
 
-        let myself = Rc::new(RefCell::new(
-            CreateEditUI {
-                window: window.clone(),
-                myself: None,
-                name: name_entry.clone(),
-                identifier: identifier.clone(),
-                kind: type_combo.clone(),
-                key_id: key_entry.clone(),
-                key_secret: secret_entry.clone(),
-                path: path.clone(),
-                password: password.clone(),
-                confirm: confirm.clone(),
-                include: vec![],
-                exclude: vec![],
-                stack: stack.clone(),
-                back_button: back_button.clone(),
-                forward_button: forward_button.clone(),
-                add_backup: add_backup.clone(),
-            }
-        ));
-
-        // This is a weird way of giving a way for method to make new clones
-        // of the Rc.
-        myself.borrow_mut().myself.replace(myself.clone());
-
         // Include.
         let vbox = gtk::Box::new(gtk::Orientation::Vertical, 16);
         vbox.set_widget_name("include_box");
@@ -281,28 +556,54 @@ impl CreateEditUI {
         spinner.start();
         vbox.append(&spinner);
 
-        let label = gtk::Label::new(Some("Initializing repository..."));
-        label.set_valign(gtk::Align::Start);
-        label.set_vexpand(true);
-        vbox.append(&label);
+        let feedback = gtk::Label::new(Some("Initializing repository..."));
+        feedback.set_valign(gtk::Align::Start);
+        feedback.set_vexpand(true);
+        vbox.append(&feedback);
+
+        let myself = Rc::new(RefCell::new(
+            CreateEditUI {
+                window: window.clone(),
+                myself: None,
+                name: name_entry.clone(),
+                identifier: identifier.clone(),
+                kind: type_combo.clone(),
+                key_id: key_entry.clone(),
+                key_secret: secret_entry.clone(),
+                region: region_entry.clone(),
+                auth_url: auth_url_entry.clone(),
+                project: project_entry.clone(),
+                user_domain: user_domain_entry.clone(),
+                project_domain: project_domain_entry.clone(),
+                ca_cert: ca_cert_entry.clone(),
+                client_cert: client_cert_entry.clone(),
+                rclone_config: rclone_config_entry.clone(),
+                path: path.clone(),
+                password: password.clone(),
+                confirm: confirm.clone(),
+                schedule: schedule.clone(),
+                include: vec![],
+                exclude: vec![],
+                include_list: include_list.clone(),
+                exclude_list: exclude_list.clone(),
+                feedback,
+                editing: None,
+                stack: stack.clone(),
+                back_button: back_button.clone(),
+                forward_button: forward_button.clone(),
+                add_backup: add_backup.clone(),
+            }
+        ));
+
+        // This is a weird way of giving a way for method to make new clones
+        // of the Rc.
+        myself.borrow_mut().myself.replace(myself.clone());
 
         // Create a local mutable borrow of self so we can properly
         // add default entries to include/exclude and connect signals.
         let mut edit_ui = myself.borrow_mut();
 
-        // FIXME: this is wrong, we need to ask the server what UID it is
-        // running with...
-        let default_include_path = match users::get_effective_uid() {
-            0 => std::path::PathBuf::from("/"),
-            _ => dirs::home_dir().unwrap(),
-        };
-
-        let default_row = edit_ui.new_include_row(default_include_path);
-        include_list.append(&default_row);
-
-        for row in edit_ui.default_exclude_patterns() {
-            exclude_list.append(&row);
-        }
+        edit_ui.fill_lists(&[default_include_path()], &default_exclude_patterns());
 
         let include_self = myself.clone();
         add_include_button.connect_clicked(
@@ -356,9 +657,10 @@ impl CreateEditUI {
 
         let add_self = myself.clone();
         add_backup.connect_clicked(
-            clone!(@weak name_entry, @weak type_combo, @weak identifier, @weak path,
-                @weak key_entry, @weak secret_entry,
-                @weak password => move |_| {
+            clone!(@weak name_entry,
+                @weak ca_cert_entry, @weak client_cert_entry,
+                @weak rclone_config_entry,
+                @weak password, @weak schedule => move |_| {
                 // Go to feedback page, so the user has some feedback that things are ongoing.
                 // Run the main loop to make sure we do that before we start the work, so there
                 // is no "hang" from the user's perspective.
@@ -367,25 +669,36 @@ impl CreateEditUI {
                     glib::MainContext::default().dispatch();
                 }
 
-                let repo_type = type_combo.active_id()
-                    .expect("Combo box should never be empty")
-                    .to_string();
-                let identifier = identifier.text().to_string();
-                let path = path.text().to_string();
                 let password = password.text().to_string();
 
-                let repository_str = format!("{}:{}:{}", repo_type, identifier, path);
-                let repository = Repository::from(repository_str.as_str());
+                // The button is only sensitive for valid repositories.
+                let repository = add_self.borrow().repository()
+                    .expect("Repository should have been validated");
 
-                let mut key_id: Option<String> = None;
-                let mut key_secret: Option<String> = None;
-                // This could be written as an if let, but we will add more cases
-                // here, so we make it a match.
+                let credentials = add_self.borrow().credentials(&repository.kind);
+
+                let mut ca_cert: Option<PathBuf> = None;
+                let mut client_cert: Option<PathBuf> = None;
+                let mut rclone: Option<RcloneOptions> = None;
                 match repository.kind {
-                    RepositoryKind::B2 => {
-                        key_id.replace(key_entry.text().to_string());
-                        key_secret.replace(secret_entry.text().to_string());
-                    }
+                    RepositoryKind::Rest => {
+                        let ca_cert_text = ca_cert_entry.text().to_string();
+                        if !ca_cert_text.trim().is_empty() {
+                            ca_cert.replace(PathBuf::from(ca_cert_text.trim()));
+                        }
+                        let client_cert_text = client_cert_entry.text().to_string();
+                        if !client_cert_text.trim().is_empty() {
+                            client_cert.replace(PathBuf::from(client_cert_text.trim()));
+                        }
+                    },
+                    RepositoryKind::Rclone => {
+                        let config_text = rclone_config_entry.text().to_string();
+                        rclone.replace(RcloneOptions {
+                            config: Some(config_text.trim())
+                                .filter(|config| !config.is_empty())
+                                .map(PathBuf::from),
+                        });
+                    },
                     _ => ()
                 }
 
@@ -399,14 +712,46 @@ impl CreateEditUI {
                     .map(|entry| entry.text().to_string())
                     .collect();
 
+                // Validity was checked when the form was filled in.
+                let schedule = schedule.text().to_string();
+                let schedule = if schedule.trim().is_empty() {
+                    None
+                } else {
+                    Schedule::from_str(&schedule).ok()
+                };
+
+                // The retention policy is not part of the form, so keep whatever
+                // the backup we are editing had.
+                let editing = add_self.borrow().editing.clone();
+                let retention = editing.as_ref()
+                    .and_then(|previous| previous.retention.clone());
+
                 let backup = Backup {
                     name: name_entry.text().to_string(),
                     repository,
-                    password,
-                    key_id,
-                    key_secret,
+                    password: password.into(),
+                    credentials,
+                    ca_cert,
+                    client_cert,
+                    rclone,
                     include,
                     exclude,
+                    retention,
+                    schedule,
+                };
+
+                let (message, failure_text) = match editing {
+                    Some(previous) => (
+                        ClientMessage::UpdateBackup(ClientMessageUpdateBackup {
+                            name: previous.name,
+                            backup,
+                        }),
+                        "Failed to save backup.",
+                    ),
+                    None => (
+                        ClientMessage::CreateBackup(ClientMessageCreateBackup {backup}),
+                        "Failed to create backup.",
+                    ),
                 };
 
                 let myself = add_self.clone();
@@ -417,28 +762,15 @@ impl CreateEditUI {
                         Err(_) => return,
                     };
 
-                    if let Err(error) = connection.send_message(
-                        ClientMessage::CreateBackup(ClientMessageCreateBackup {backup})
-                    ).await { println!("Error creating backup: {:#?}", error) }
+                    if let Err(error) = connection.send_message(message).await {
+                        println!("Error saving backup: {:#?}", error)
+                    }
 
                     match connection.read_response().await {
                         Ok(response) => {
                             if let Some(error) = response.error {
-                                let error = match error {
-                                    ServerError::Configuration(e) |
-                                    ServerError::RepoInit(e) => e,
-                                };
                                 let parent_window = myself.borrow().window.clone();
-                                let dialog = gtk::MessageDialogBuilder::new()
-                                    .transient_for(&parent_window)
-                                    .modal(true)
-                                    .message_type(gtk::MessageType::Error)
-                                    .buttons(gtk::ButtonsType::Close)
-                                    .text("Failed to create backup.")
-                                    .secondary_text(&error)
-                                    .build();
-                                dialog.run_future().await;
-                                dialog.close();
+                                show_error(&parent_window, failure_text, &error).await;
 
                                 // If adding the backup failed, the error lies in the repository
                                 // definition.
@@ -460,9 +792,11 @@ impl CreateEditUI {
         // Disable or enable add_backup based on various inputs.
         let entries = vec![
             edit_ui.name.clone().upcast::<gtk::Editable>(),
+            edit_ui.identifier.clone().upcast::<gtk::Editable>(),
             edit_ui.path.clone().upcast::<gtk::Editable>(),
             edit_ui.password.clone().upcast::<gtk::Editable>(),
             edit_ui.confirm.clone().upcast::<gtk::Editable>(),
+            edit_ui.schedule.clone().upcast::<gtk::Editable>(),
         ];
 
         for entry in entries {
@@ -478,6 +812,14 @@ impl CreateEditUI {
             });
         }
 
+        // Whether the repository is valid depends on its kind, too.
+        let kind_edit_ui = myself.clone();
+        edit_ui.kind.connect_changed(move |_| {
+            if let Ok(edit_ui) = kind_edit_ui.try_borrow() {
+                edit_ui.update_state();
+            }
+        });
+
         // Explicitly drop the borrow so we can move self out.
         drop(edit_ui);
 
@@ -515,41 +857,69 @@ impl CreateEditUI {
         self.update_state();
     }
 
-    pub fn open(&self) {
+    pub fn open(&mut self) {
+        // Start from a clean form if it was last used to edit a backup.
+        if self.editing.is_some() {
+            self.reset();
+        }
         self.window.present();
     }
 
-    fn default_exclude_patterns(&mut self) -> Vec<gtk::ListBoxRow> {
-        let mut patterns = Vec::<String>::new();
-        if cfg!(target_os = "macos") {
-            let home_dir = dirs::home_dir()
-                .unwrap()
-                .to_string_lossy()
-                .to_string();
-
-            // FIXME: we need to ask the server if it running in privileged mode to
-            // filter which patterns to use as default.
-            &[
-                ".cache",
-                "Caches",
-                &format!("{}/.Trash", home_dir),
-                &format!("{}/Library", home_dir),
-                "/private/var/folders",
-                "/private/var/networkd/db",
-                "/private/var/protected/trustd/private",
-                "/private/var/db"
-            ].iter().for_each(|p| patterns.push(p.to_string()));
+    pub fn open_for_edit(&mut self, backup: &BackupSummary) {
+        self.reset();
+
+        self.name.set_text(&backup.name);
+        self.kind.set_active_id(Some(&backup.repository.kind.to_string()));
+        self.identifier.set_text(&backup.repository.identifier);
+        self.fill_credentials(&backup.credentials);
+        self.ca_cert.set_text(
+            &backup.ca_cert.as_ref().map(|path| path.to_string_lossy().to_string()).unwrap_or_default()
+        );
+        self.client_cert.set_text(
+            &backup.client_cert.as_ref().map(|path| path.to_string_lossy().to_string()).unwrap_or_default()
+        );
+        let rclone = backup.rclone.clone().unwrap_or_default();
+        self.rclone_config.set_text(
+            &rclone.config.map(|path| path.to_string_lossy().to_string()).unwrap_or_default()
+        );
+        self.path.set_text(&backup.repository.path);
+        // The daemon does not tell us the secrets; leaving them empty keeps them.
+        self.password.set_placeholder_text(Some("Unchanged"));
+        self.confirm.set_placeholder_text(Some("Unchanged"));
+        if backup.credentials.kind().is_some() {
+            self.key_secret.set_placeholder_text(Some("Unchanged"));
+        }
+        self.schedule.set_text(
+            &backup.schedule.as_ref().map(|schedule| schedule.to_string()).unwrap_or_default()
+        );
+        self.fill_lists(&backup.include, &backup.exclude);
+
+        self.editing.replace(backup.clone());
+        self.add_backup.set_label("Save Backup");
+        self.feedback.set_text("Saving backup...");
+        self.update_state();
+
+        self.window.present();
+    }
+
+    fn fill_lists(&mut self, include: &[PathBuf], exclude: &[String]) {
+        for list in &[&self.include_list, &self.exclude_list] {
+            while let Some(row) = list.row_at_index(0) {
+                list.remove(&row);
+            }
         }
+        self.include.clear();
+        self.exclude.clear();
 
-        if cfg!(target_os = "linux") {
-            patterns.push(".cache".to_string());
-            patterns.push("/var/lib/systemd/coredump".to_string());
+        for path in include {
+            let row = self.new_include_row(path.clone());
+            self.include_list.append(&row);
         }
 
-        patterns
-            .iter()
-            .map(|p| self.new_exclude_row(&p))
-            .collect()
+        for pattern in exclude {
+            let row = self.new_exclude_row(pattern);
+            self.exclude_list.append(&row);
+        }
     }
 
     fn new_exclude_row(&mut self, initial_text: &str) -> gtk::ListBoxRow {
@@ -688,7 +1058,16 @@ impl CreateEditUI {
 
         let password = self.password.text().to_string();
         let confirm = self.confirm.text().to_string();
-        if password.is_empty() || password != confirm {
+        if (password.is_empty() && self.editing.is_none()) || password != confirm {
+            sensitive = false;
+        }
+
+        let schedule = self.schedule.text().to_string();
+        if !schedule.trim().is_empty() && Schedule::from_str(&schedule).is_err() {
+            sensitive = false;
+        }
+
+        if self.repository().is_err() {
             sensitive = false;
         }
 
@@ -696,20 +1075,135 @@ impl CreateEditUI {
         self.add_backup.set_sensitive(sensitive);
     }
 
+    fn repository(&self) -> Result<Repository, RepositoryParseError> {
+        let kind = RepositoryKind::from_str(
+            self.kind.active_id().expect("Combo box should never be empty").as_str()
+        ).unwrap();
+
+        // The identifier entry is hidden, but may still have text, for local ones.
+        let identifier = match kind {
+            RepositoryKind::Local => String::new(),
+            _ => self.identifier.text().to_string(),
+        };
+
+        let repository = Repository {
+            kind,
+            identifier,
+            path: self.path.text().to_string(),
+        };
+        repository.validate()?;
+        Ok(repository)
+    }
+
+    fn credentials(&self, kind: &RepositoryKind) -> Credentials {
+        let text = |entry: &gtk::Entry| entry.text().to_string();
+        let trimmed = |entry: &gtk::Entry| entry.text().trim().to_string();
+
+        let credentials = match kind {
+            RepositoryKind::B2 => Credentials::B2 {
+                account_id: trimmed(&self.key_id),
+                account_key: text(&self.key_secret).into(),
+            },
+            RepositoryKind::S3 => Credentials::S3 {
+                access_key_id: trimmed(&self.key_id),
+                secret_access_key: text(&self.key_secret).into(),
+                region: trimmed(&self.region),
+            },
+            RepositoryKind::Rest => Credentials::Rest {
+                username: text(&self.key_id),
+                password: text(&self.key_secret).into(),
+            },
+            RepositoryKind::Azure => Credentials::Azure {
+                account_name: trimmed(&self.key_id),
+                account_key: text(&self.key_secret).into(),
+            },
+            RepositoryKind::GCS => Credentials::GCS {
+                project_id: trimmed(&self.key_id),
+                credentials_file: PathBuf::from(trimmed(&self.key_secret)),
+            },
+            RepositoryKind::Swift => Credentials::Swift {
+                auth_url: trimmed(&self.auth_url),
+                username: text(&self.key_id),
+                password: text(&self.key_secret).into(),
+                region_name: trimmed(&self.region),
+                user_domain_name: trimmed(&self.user_domain),
+                project_name: trimmed(&self.project),
+                project_domain_name: trimmed(&self.project_domain),
+            },
+            RepositoryKind::Local | RepositoryKind::SFTP | RepositoryKind::Rclone => Credentials::None,
+        };
+
+        // Same as what the daemon reads back when nothing was filled in.
+        if credentials.to_environment().is_empty() {
+            Credentials::None
+        } else {
+            credentials
+        }
+    }
+
+    fn fill_credentials(&self, credentials: &Credentials) {
+        match credentials {
+            Credentials::None => (),
+            Credentials::B2 { account_id: key, account_key: secret } |
+            Credentials::Rest { username: key, password: secret } |
+            Credentials::Azure { account_name: key, account_key: secret } => {
+                self.key_id.set_text(key);
+                self.key_secret.set_text(secret.expose());
+            },
+            Credentials::S3 { access_key_id, secret_access_key, region } => {
+                self.key_id.set_text(access_key_id);
+                self.key_secret.set_text(secret_access_key.expose());
+                self.region.set_text(region);
+            },
+            Credentials::GCS { project_id, credentials_file } => {
+                self.key_id.set_text(project_id);
+                self.key_secret.set_text(&credentials_file.to_string_lossy());
+            },
+            Credentials::Swift {
+                auth_url, username, password, region_name,
+                user_domain_name, project_name, project_domain_name,
+            } => {
+                self.auth_url.set_text(auth_url);
+                self.key_id.set_text(username);
+                self.key_secret.set_text(password.expose());
+                self.region.set_text(region_name);
+                self.user_domain.set_text(user_domain_name);
+                self.project.set_text(project_name);
+                self.project_domain.set_text(project_domain_name);
+            },
+        }
+    }
+
     fn clear(&mut self) {
         self.window.hide();
+        self.reset();
+    }
 
+    fn reset(&mut self) {
         self.name.set_text("");
-        self.kind.set_active_id(Some("Local"));
+        self.kind.set_active_id(Some("local"));
         self.identifier.set_text("");
         self.key_id.set_text("");
         self.key_secret.set_text("");
+        self.region.set_text("");
+        self.auth_url.set_text("");
+        self.project.set_text("");
+        self.user_domain.set_text("");
+        self.project_domain.set_text("");
+        self.ca_cert.set_text("");
+        self.client_cert.set_text("");
+        self.rclone_config.set_text("");
         self.path.set_text("");
         self.password.set_text("");
+        self.password.set_placeholder_text(None);
+        self.confirm.set_placeholder_text(None);
         self.confirm.set_text("");
-        self.include.iter_mut()
-            .for_each(|entry| entry.set_text(""));
+        self.schedule.set_text("");
+        self.fill_lists(&[default_include_path()], &default_exclude_patterns());
+        self.editing = None;
+        self.add_backup.set_label("Add Backup");
         self.add_backup.set_sensitive(false);
+        self.feedback.set_text("Initializing repository...");
 
         self.stack.set_visible_child_name("repository");
         self.update_state();
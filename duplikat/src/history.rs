@@ -0,0 +1,236 @@
+use std::{cell::RefCell, rc::Rc};
+use duplikat_types::*;
+use glib::MainContext;
+use gtk::prelude::*;
+use crate::Application;
+use crate::server::Server;
+use crate::utils::{explain_error, format_time, to_human_readable};
+
+// How many of the most recent runs we ask the daemon for.
+const HISTORY_LIMIT: usize = 50;
+
+pub struct HistoryUI {
+    pub window: gtk::Dialog,
+    application: Rc<RefCell<Application>>,
+    runs: gtk::ListBox,
+}
+
+/// The entries of the backup's log that were written while the run went on.
+fn log_for_run<'a>(run: &RunRecord, entries: &'a [LogEntry]) -> Vec<&'a LogEntry> {
+    let parse = |time: &str| glib::DateTime::from_iso8601(time, None).ok();
+    let (started, finished) = match (parse(&run.started), parse(&run.finished)) {
+        (Some(started), Some(finished)) => (started, finished),
+        _ => return vec![],
+    };
+
+    entries.iter()
+        .filter(|entry| match parse(&entry.time) {
+            Some(time) => time >= started && time <= finished,
+            None => false,
+        })
+        .collect()
+}
+
+fn new_run_row(run: &RunRecord, log: &[&LogEntry]) -> gtk::ListBoxRow {
+    let row = gtk::ListBoxRowBuilder::new()
+        .activatable(false)
+        .selectable(false)
+        .build();
+
+    let vbox = gtk::Box::new(gtk::Orientation::Vertical, 8);
+    row.set_child(Some(&vbox));
+
+    // Outcome and when it happened.
+    let top = gtk::Box::new(gtk::Orientation::Horizontal, 16);
+    vbox.append(&top);
+
+    let (icon_name, outcome) = match run.outcome {
+        RunOutcome::Succeeded => ("emblem-ok-symbolic", "Succeeded"),
+        RunOutcome::Partial => ("dialog-warning-symbolic", "Incomplete"),
+        RunOutcome::Failed => ("dialog-error-symbolic", "Failed"),
+        RunOutcome::Cancelled => ("process-stop-symbolic", "Cancelled"),
+    };
+    top.append(&gtk::Image::from_icon_name(Some(icon_name)));
+
+    let label = gtk::Label::new(None);
+    label.set_markup(&format!("<b>{}</b>", outcome));
+    top.append(&label);
+
+    let trigger = match run.trigger {
+        RunTrigger::Manual => "started manually",
+        RunTrigger::Scheduled => "scheduled",
+    };
+    let time = gtk::Label::new(Some(&format!("{} ({})", format_time(&run.started), trigger)));
+    time.set_halign(gtk::Align::End);
+    time.set_hexpand(true);
+    top.append(&time);
+
+    // What restic did, or why it did not.
+    if let Some(summary) = &run.summary {
+        let details = gtk::Label::new(Some(&format!(
+            "{} new, {} changed and {} unmodified files, {} added, snapshot {}",
+            summary.files_new,
+            summary.files_changed,
+            summary.files_unmodified,
+            to_human_readable(summary.data_added),
+            summary.snapshot_id.chars().take(8).collect::<String>(),
+        )));
+        details.set_halign(gtk::Align::Start);
+        vbox.append(&details);
+    }
+
+    if let Some(error) = &run.error {
+        let error = gtk::Label::new(Some(&explain_error(error)));
+        error.set_halign(gtk::Align::Start);
+        error.set_wrap(true);
+        error.set_selectable(true);
+        vbox.append(&error);
+    }
+
+    if !run.errors.is_empty() {
+        let expander = gtk::Expander::new(Some(&format!("Could not back up ({})", run.errors.len())));
+        vbox.append(&expander);
+
+        let errors = run.errors.iter()
+            .map(|error| match error.item.as_str() {
+                "" => error.message.clone(),
+                item => format!("{}: {}", item, error.message),
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let errors = gtk::Label::new(Some(&errors));
+        errors.set_halign(gtk::Align::Start);
+        errors.set_wrap(true);
+        errors.set_selectable(true);
+        expander.set_child(Some(&errors));
+    }
+
+    if !run.stderr.is_empty() {
+        let expander = gtk::Expander::new(Some("Output"));
+        vbox.append(&expander);
+
+        let output = gtk::Label::new(Some(&run.stderr));
+        output.set_halign(gtk::Align::Start);
+        output.set_wrap(true);
+        output.set_selectable(true);
+        output.set_css_classes(&["monospace"]);
+        expander.set_child(Some(&output));
+    }
+
+    if !log.is_empty() {
+        let expander = gtk::Expander::new(Some("Log"));
+        vbox.append(&expander);
+
+        let log = log.iter()
+            .map(|entry| format!("{} {}", entry.level, entry.message))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let log = gtk::Label::new(Some(&log));
+        log.set_halign(gtk::Align::Start);
+        log.set_wrap(true);
+        log.set_selectable(true);
+        log.set_css_classes(&["monospace"]);
+        expander.set_child(Some(&log));
+    }
+
+    row
+}
+
+impl HistoryUI {
+    pub(crate) fn new(application: Rc<RefCell<Application>>) -> Rc<RefCell<Self>> {
+        let window = gtk::DialogBuilder::new()
+            .transient_for(&application.borrow().main_window)
+            .hide_on_close(true)
+            .use_header_bar(1)
+            .modal(true)
+            .default_width(600)
+            .default_height(400)
+            .build();
+
+        let scrolled = gtk::ScrolledWindow::new();
+        scrolled.set_policy(gtk::PolicyType::Never, gtk::PolicyType::Automatic);
+        scrolled.set_vexpand(true);
+        window.set_child(Some(&scrolled));
+
+        let runs = gtk::ListBox::new();
+        runs.set_widget_name("history_runs");
+        runs.set_selection_mode(gtk::SelectionMode::None);
+        runs.set_show_separators(true);
+        runs.set_css_classes(&["rich-list"]);
+        runs.set_placeholder(Some(&gtk::Label::new(Some("No runs recorded yet."))));
+        scrolled.set_child(Some(&runs));
+
+        Rc::new(RefCell::new(
+            HistoryUI {
+                window,
+                application,
+                runs,
+            }
+        ))
+    }
+
+    pub fn open(&self, name: &str) {
+        self.window.set_title(Some(&format!("History of {}", name)));
+        while let Some(row) = self.runs.row_at_index(0) {
+            self.runs.remove(&row);
+        }
+        self.window.present();
+
+        let application = self.application.clone();
+        let runs = self.runs.clone();
+        let name = name.to_string();
+        MainContext::default().spawn_local(async move {
+            let connection = match Server::connect(application.clone()).await {
+                Ok(c) => c,
+                Err(_) => return,
+            };
+
+            if let Err(error) = connection.send_message(
+                ClientMessage::GetRunHistory(ClientMessageGetRunHistory {
+                    name: name.clone(),
+                    limit: Some(HISTORY_LIMIT),
+                })
+            ).await {
+                println!("Error getting run history: {:#?}", error);
+                return;
+            };
+
+            let history = match connection.read_message().await {
+                Ok(Some(ResticMessage::RunHistory(history))) => history,
+                _ => return,
+            };
+
+            // The log usually says more about why a run failed than its record.
+            let entries = match history.runs.last() {
+                Some(oldest) => Self::logs(application, name, oldest.started.clone()).await,
+                None => vec![],
+            };
+
+            for run in &history.runs {
+                runs.append(&new_run_row(run, &log_for_run(run, &entries)));
+            }
+        });
+    }
+
+    async fn logs(application: Rc<RefCell<Application>>, name: String, since: String) -> Vec<LogEntry> {
+        let connection = match Server::connect(application).await {
+            Ok(c) => c,
+            Err(_) => return vec![],
+        };
+
+        if let Err(error) = connection.send_message(
+            ClientMessage::GetLogs(ClientMessageGetLogs {
+                name,
+                since: Some(since),
+            })
+        ).await {
+            println!("Error getting logs: {:#?}", error);
+            return vec![];
+        };
+
+        match connection.read_message().await {
+            Ok(Some(ResticMessage::Logs(logs))) => logs.entries,
+            _ => vec![],
+        }
+    }
+}
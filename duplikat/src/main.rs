@@ -3,7 +3,9 @@ use std::rc::Rc;
 use gtk::prelude::*;
 
 mod server;
+mod browse;
 mod edit;
+mod history;
 mod overview;
 mod utils;
 
@@ -14,6 +16,8 @@ pub struct Application {
     pub create_button: gtk::Button,
     pub overview: Option<Rc<RefCell<overview::OverviewUI>>>,
     pub create_edit: Option<Rc<RefCell<edit::CreateEditUI>>>,
+    pub browser: Option<Rc<RefCell<browse::SnapshotBrowserUI>>>,
+    pub history: Option<Rc<RefCell<history::HistoryUI>>>,
 }
 
 impl Application {
@@ -27,12 +31,26 @@ impl Application {
                 create_button,
                 overview: None,
                 create_edit: None,
+                browser: None,
+                history: None,
             }
         ))
     }
 
     fn open_create_edit(&self) {
-        self.create_edit.as_ref().unwrap().borrow().open();
+        self.create_edit.as_ref().unwrap().borrow_mut().open();
+    }
+
+    fn open_edit(&self, backup: &duplikat_types::BackupSummary) {
+        self.create_edit.as_ref().unwrap().borrow_mut().open_for_edit(backup);
+    }
+
+    fn open_browser(&self, name: &str) {
+        self.browser.as_ref().unwrap().borrow_mut().open(name);
+    }
+
+    fn open_history(&self, name: &str) {
+        self.history.as_ref().unwrap().borrow().open(name);
     }
 
     fn update(&mut self) {
@@ -93,9 +111,18 @@ fn create_ui(app: &gtk::Application) {
     application.borrow_mut().overview.replace(overview.clone());
     stack.add_titled(&overview.borrow().container, Some("overview"), "Backups Overview");
 
+    // Snapshot browser
+    let browser = browse::SnapshotBrowserUI::new(application.clone());
+    application.borrow_mut().browser.replace(browser.clone());
+    stack.add_titled(&browser.borrow().container, Some("browser"), "Snapshot Browser");
+
     // Create/edit backup
     let create_edit = edit::CreateEditUI::new(application.clone());
     application.borrow_mut().create_edit.replace(create_edit.clone());
 
+    // Run history
+    let history = history::HistoryUI::new(application.clone());
+    application.borrow_mut().history.replace(history.clone());
+
     window.present();
 }
@@ -1,10 +1,10 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{cell::{Cell, RefCell}, collections::HashMap, rc::Rc};
 use duplikat_types::*;
-use glib::{MainContext, clone};
+use glib::MainContext;
 use gtk::prelude::*;
 use crate::Application;
 use crate::server::Server;
-use crate::utils::next_row_num;
+use crate::utils::{format_time, next_row_num, show_error, to_human_readable};
 
 pub struct OverviewUI {
     pub container: gtk::ListBox,
@@ -15,18 +15,76 @@ pub struct OverviewUI {
 struct BackupRow {
     bytes: gtk::Label,
     files: gtk::Label,
+    progress_bar: gtk::ProgressBar,
+    run_button: gtk::Button,
+    cancel_button: gtk::Button,
+    warning: gtk::Label,
+    // Files the running backup could not read so far.
+    errors: Cell<usize>,
 }
 
-fn to_human_readable(bytes: u64) -> String {
-    let tiers = vec!["KiB", "MiB", "GiB", "TiB"];
-    let mut bytes = bytes as f64;
-    for tier in tiers {
-        bytes /= 1024f64;
-        if bytes < 1000f64 {
-            return format!("{:.2} {}", bytes, tier);
+impl BackupRow {
+    fn show_status(&self, status: &ResticMessageStatus) {
+        self.run_button.set_visible(false);
+        self.cancel_button.set_visible(true);
+        self.progress_bar.set_visible(true);
+        self.progress_bar.set_fraction(status.percent_done);
+        if let Some(seconds) = status.seconds_remaining {
+            let time_str = seconds_to_human_readable(seconds);
+            self.progress_bar.set_text(Some(
+                &format!("{}% ({} left)",
+                    (status.percent_done * 100f64) as u64,
+                    time_str
+                )
+            ));
         }
     }
-    "NaN".to_string()
+
+    fn show_error(&self, error: &ResticMessageError) {
+        self.errors.set(self.errors.get() + 1);
+        self.warning.set_text(&format!("Could not read {} so far", files(self.errors.get())));
+        self.warning.set_tooltip_text(Some(&error.message));
+        self.warning.set_visible(true);
+    }
+
+    /// Warns about the last run if it did not go well.
+    fn show_last_run(&self, run: &RunRecord) {
+        let message = run.error.as_ref().map(|error| error.to_string()).unwrap_or_default();
+        let text = match run.outcome {
+            RunOutcome::Partial => format!("Last backup is incomplete: {}", message),
+            RunOutcome::Failed => format!("Last backup failed: {}", message),
+            RunOutcome::Succeeded | RunOutcome::Cancelled => return,
+        };
+        self.warning.set_text(&text);
+
+        let items: Vec<&str> = run.errors.iter()
+            .map(|error| error.item.as_str())
+            .filter(|item| !item.is_empty())
+            .collect();
+        if !items.is_empty() {
+            self.warning.set_tooltip_text(Some(&items.join("\n")));
+        }
+        self.warning.set_visible(true);
+    }
+
+    fn reset(&self) {
+        self.run_button.set_visible(true);
+        self.cancel_button.set_visible(false);
+        self.cancel_button.set_sensitive(true);
+        self.progress_bar.set_visible(false);
+        self.progress_bar.set_fraction(0.);
+        self.errors.set(0);
+        self.warning.set_visible(false);
+        self.warning.set_tooltip_text(None);
+    }
+}
+
+fn files(count: usize) -> String {
+    if count > 1 {
+        format!("{} files", count)
+    } else {
+        format!("{} file", count)
+    }
 }
 
 fn seconds_to_human_readable(seconds: u64) -> String {
@@ -76,10 +134,53 @@ impl OverviewUI {
         }));
 
         overview.borrow().update();
+        overview.borrow().subscribe();
 
         overview
     }
 
+    /// Follows every backup the daemon runs, whoever started it, so that we
+    /// also show progress for backups that were running before we were.
+    fn subscribe(&self) {
+        let application = self.application.clone();
+        MainContext::default().spawn_local(async move {
+            let connection = match Server::connect(application.clone()).await {
+                Ok(c) => c,
+                Err(_) => return,
+            };
+
+            if let Err(error) = connection.send_message(ClientMessage::SubscribeEvents).await {
+                println!("Error subscribing to events: {:#?}", error);
+                return;
+            };
+
+            while let Ok(Some(message)) = connection.read_message().await {
+                let overview = application.borrow().overview.as_ref().unwrap().clone();
+                let overview = overview.borrow();
+                match message {
+                    ResticMessage::Status(status) => {
+                        // Rows only show up once the list of backups is in.
+                        if let Some(row) = status.name.as_ref().and_then(|name| overview.rows.get(name)) {
+                            row.show_status(&status);
+                        }
+                    },
+                    ResticMessage::Error(error) => {
+                        if let Some(row) = error.name.as_ref().and_then(|name| overview.rows.get(name)) {
+                            row.show_error(&error);
+                        }
+                    },
+                    ResticMessage::RunFinished(finished) => {
+                        if let Some(row) = overview.rows.get(&finished.name) {
+                            row.reset();
+                        }
+                        overview.update();
+                    },
+                    _ => (),
+                }
+            }
+        });
+    }
+
     pub fn update(&self) {
         let a = self.application.clone();
         MainContext::default().spawn_local(
@@ -105,7 +206,9 @@ impl OverviewUI {
                         match message {
                             ResticMessage::BackupsList(backups) => {
                                 for backup in backups.list {
-                                    let row = overview.borrow_mut().create_row_for_backup(&backup).clone();
+                                    let next_run = backups.next_runs.get(&backup.name);
+                                    let last_run = backups.last_runs.get(&backup.name);
+                                    let row = overview.borrow_mut().create_row_for_backup(&backup, next_run, last_run).clone();
                                     listbox.append(&row);
                                 }
                             },
@@ -129,7 +232,7 @@ impl OverviewUI {
         );
     }
 
-    fn create_row_for_backup(&mut self, backup: &Backup) -> gtk::ListBoxRow {
+    fn create_row_for_backup(&mut self, backup: &BackupSummary, next_run: Option<&String>, last_run: Option<&RunRecord>) -> gtk::ListBoxRow {
         let row = gtk::ListBoxRow::new();
 
         let frame = gtk::Frame::new(Some(&backup.name));
@@ -163,6 +266,54 @@ impl OverviewUI {
                     )
                 );
             },
+            RepositoryKind::Rest => {
+                type_text.push_str(
+                    &format!("<b>REST Server</b> ({}/{})",
+                        &backup.repository.identifier,
+                        &backup.repository.path
+                    )
+                );
+            },
+            RepositoryKind::Rclone => {
+                type_text.push_str(
+                    &format!("<b>rclone</b> ({}:{})",
+                        &backup.repository.identifier,
+                        &backup.repository.path
+                    )
+                );
+            },
+            RepositoryKind::Azure => {
+                type_text.push_str(
+                    &format!("<b>Azure Blob Storage</b> ({}:{})",
+                        &backup.repository.identifier,
+                        &backup.repository.path
+                    )
+                );
+            },
+            RepositoryKind::GCS => {
+                type_text.push_str(
+                    &format!("<b>Google Cloud Storage</b> ({}:{})",
+                        &backup.repository.identifier,
+                        &backup.repository.path
+                    )
+                );
+            },
+            RepositoryKind::Swift => {
+                type_text.push_str(
+                    &format!("<b>OpenStack Swift</b> ({}:{})",
+                        &backup.repository.identifier,
+                        &backup.repository.path
+                    )
+                );
+            },
+            RepositoryKind::S3 => {
+                type_text.push_str(
+                    &format!("<b>S3</b> ({}/{})",
+                        &backup.repository.identifier,
+                        &backup.repository.path
+                    )
+                );
+            },
         }
 
         let mut row_num = -1i32;
@@ -188,6 +339,17 @@ impl OverviewUI {
 
         grid.attach_next_to(&bytes_label, Some(&label), gtk::PositionType::Right, 1, 1);
 
+        let edit_button = gtk::Button::with_label("Edit");
+        edit_button.set_halign(gtk::Align::End);
+        edit_button.set_hexpand(true);
+
+        grid.attach_next_to(&edit_button, Some(&bytes_label), gtk::PositionType::Right, 1, 1);
+
+        let delete_button = gtk::Button::with_label("Delete");
+        delete_button.set_css_classes(&["destructive-action"]);
+
+        grid.attach_next_to(&delete_button, Some(&edit_button), gtk::PositionType::Right, 1, 1);
+
         let label = gtk::Label::new(None);
         label.set_markup("<b>File count:</b>");
 
@@ -197,6 +359,27 @@ impl OverviewUI {
 
         grid.attach_next_to(&files_label, Some(&label), gtk::PositionType::Right, 1, 1);
 
+        let browse_button = gtk::Button::with_label("Browse snapshots");
+        browse_button.set_halign(gtk::Align::End);
+        browse_button.set_hexpand(true);
+
+        grid.attach_next_to(&browse_button, Some(&files_label), gtk::PositionType::Right, 1, 1);
+
+        let history_button = gtk::Button::with_label("History");
+
+        grid.attach_next_to(&history_button, Some(&browse_button), gtk::PositionType::Right, 1, 1);
+
+        if let Some(next_run) = next_run {
+            let label = gtk::Label::new(None);
+            label.set_markup("<b>Next backup:</b>");
+
+            grid.attach(&label, 0, next_row_num(&mut row_num), 1, 1);
+
+            let next_run_label = gtk::Label::new(Some(&format_time(next_run)));
+
+            grid.attach_next_to(&next_run_label, Some(&label), gtk::PositionType::Right, 1, 1);
+        }
+
         let progress_bar = gtk::ProgressBar::new();
         progress_bar.set_show_text(true);
         progress_bar.set_halign(gtk::Align::Fill);
@@ -219,65 +402,187 @@ impl OverviewUI {
 
         grid.attach_next_to(&cancel_button, Some(&progress_bar), gtk::PositionType::Right, 1, 1);
 
+        let warning = gtk::Label::new(None);
+        warning.set_visible(false);
+        warning.set_halign(gtk::Align::Start);
+        warning.set_wrap(true);
+        warning.set_css_classes(&["warning"]);
+
+        grid.attach(&warning, 0, next_row_num(&mut row_num), 4, 1);
+
         // Make an owned instance so that it can be moved into the closure.
         let application = self.application.clone();
         let backup_name = backup.name.clone();
-        run_button.connect_clicked(
-            clone!(@weak progress_bar, @weak cancel_button => move |button| {
-                let application = application.clone();
-                let name = backup_name.clone();
-                let button = button.clone();
-                MainContext::default().spawn_local(async move {
-                    let run_backup_message = ClientMessage::RunBackup(
-                        ClientMessageRunBackup {
-                            name,
+        browse_button.connect_clicked(move |_| {
+            application.borrow().open_browser(&backup_name);
+        });
+
+        let application = self.application.clone();
+        let backup_name = backup.name.clone();
+        history_button.connect_clicked(move |_| {
+            application.borrow().open_history(&backup_name);
+        });
+
+        let application = self.application.clone();
+        let edited_backup = backup.clone();
+        edit_button.connect_clicked(move |_| {
+            application.borrow().open_edit(&edited_backup);
+        });
+
+        let application = self.application.clone();
+        let backup_name = backup.name.clone();
+        let is_local = backup.repository.kind == RepositoryKind::Local;
+        delete_button.connect_clicked(move |_| {
+            let application = application.clone();
+            let name = backup_name.clone();
+            MainContext::default().spawn_local(async move {
+                let parent_window = application.borrow().main_window.clone();
+                let dialog = gtk::MessageDialogBuilder::new()
+                    .transient_for(&parent_window)
+                    .modal(true)
+                    .message_type(gtk::MessageType::Question)
+                    .text(&format!("Delete {}?", name))
+                    .secondary_text("It will no longer be backed up, and its settings will be lost.")
+                    .build();
+                dialog.add_button("Cancel", gtk::ResponseType::Cancel);
+                dialog.add_button("Delete", gtk::ResponseType::Accept)
+                    .set_css_classes(&["destructive-action"]);
+
+                // Only local repositories can be purged by the daemon.
+                let purge_check = gtk::CheckButton::with_label("Also delete all snapshots in the repository");
+                if is_local {
+                    dialog.message_area()
+                        .downcast::<gtk::Box>()
+                        .unwrap()
+                        .append(&purge_check);
+                }
+
+                let response = dialog.run_future().await;
+                let purge_repository = purge_check.is_active();
+                dialog.close();
+
+                if response != gtk::ResponseType::Accept {
+                    return;
+                }
+
+                let connection = match Server::connect(application.clone()).await {
+                    Ok(c) => c,
+                    Err(_) => return,
+                };
+
+                if let Err(error) = connection.send_message(
+                    ClientMessage::DeleteBackup(ClientMessageDeleteBackup { name, purge_repository })
+                ).await {
+                    println!("Failed to delete...: {:#?}", error);
+                    return;
+                };
+
+                match connection.read_response().await {
+                    Ok(response) => {
+                        if let Some(error) = response.error {
+                            show_error(&parent_window, "Failed to delete backup.", &error).await;
                         }
-                    );
+                    },
+                    Err(error) => {
+                        println!("Failed to delete...: {:#?}", error);
+                    },
+                };
 
-                    let connection = match Server::connect(application.clone()).await {
-                        Ok(c) => c,
-                        Err(_) => return,
-                    };
+                // Even if it failed, the repository may have been purged.
+                application.borrow().overview.as_ref().unwrap().borrow().update();
+            });
+        });
 
-                    if let Err(error) = connection.send_message(run_backup_message).await {
-                        println!("Failed to run...: {:#?}", error);
-                        return;
-                    };
-
-                    while let Ok(message) = connection.read_message().await {
-                        if let Some(message) = message {
-                            match message {
-                                ResticMessage::Status(status) => {
-                                    button.set_visible(false);
-                                    cancel_button.set_visible(true);
-                                    progress_bar.set_visible(true);
-                                    progress_bar.set_fraction(status.percent_done);
-                                    if let Some(seconds) = status.seconds_remaining {
-                                        let time_str = seconds_to_human_readable(seconds);
-                                        progress_bar.set_text(Some(
-                                            &format!("{}% ({} left)",
-                                                (status.percent_done * 100f64) as u64,
-                                                time_str
-                                            )
-                                        ));
-                                    }
-                                },
-                                ResticMessage::Summary(_) => {
-                                    button.set_visible(true);
-                                    cancel_button.set_visible(false);
-                                    progress_bar.set_visible(false);
-                                    progress_bar.set_fraction(0.);
-                                    application.borrow().overview.as_ref().unwrap().borrow().update();
-                                },
-                                _ => unimplemented!(),
-                            }
-                        } else {
-                            break;
+        let application = self.application.clone();
+        let backup_name = backup.name.clone();
+        cancel_button.connect_clicked(move |button| {
+            let application = application.clone();
+            let name = backup_name.clone();
+            let button = button.clone();
+
+            // The backup row is reset once the daemon tells us the run was
+            // cancelled, so just make sure we are not asked twice meanwhile.
+            button.set_sensitive(false);
+
+            MainContext::default().spawn_local(async move {
+                let cancel_backup_message = ClientMessage::CancelBackup(
+                    ClientMessageCancelBackup {
+                        name,
+                    }
+                );
+
+                let connection = match Server::connect(application.clone()).await {
+                    Ok(c) => c,
+                    Err(_) => return,
+                };
+
+                if let Err(error) = connection.send_message(cancel_backup_message).await {
+                    println!("Failed to cancel...: {:#?}", error);
+                    button.set_sensitive(true);
+                    return;
+                };
+
+                match connection.read_response().await {
+                    Ok(response) => {
+                        if let Some(error) = response.error {
+                            button.set_sensitive(true);
+                            let parent_window = application.borrow().main_window.clone();
+                            show_error(&parent_window, "Failed to cancel backup.", &error).await;
                         }
+                    },
+                    Err(error) => {
+                        println!("Failed to cancel...: {:#?}", error);
+                        button.set_sensitive(true);
+                    },
+                };
+            });
+        });
+
+        let application = self.application.clone();
+        let backup_name = backup.name.clone();
+        run_button.connect_clicked(move |button| {
+            let application = application.clone();
+            let name = backup_name.clone();
+            let button = button.clone();
+
+            // Progress comes in through our event subscription, so all we need
+            // to know here is whether the backup started.
+            button.set_sensitive(false);
+
+            MainContext::default().spawn_local(async move {
+                let run_backup_message = ClientMessage::RunBackup(
+                    ClientMessageRunBackup {
+                        name,
                     }
-                });
-            })
-        );
+                );
+
+                let connection = match Server::connect(application.clone()).await {
+                    Ok(c) => c,
+                    Err(_) => return,
+                };
+
+                if let Err(error) = connection.send_message(run_backup_message).await {
+                    println!("Failed to run...: {:#?}", error);
+                    button.set_sensitive(true);
+                    return;
+                };
+
+                // The stream may also end before anything is sent, if the
+                // backup is done by then.
+                match connection.read_message_or_error().await {
+                    Ok(Some(Err(error))) => {
+                        let parent_window = application.borrow().main_window.clone();
+                        show_error(&parent_window, "Failed to start backup.", &error).await;
+                    },
+                    Ok(Some(Ok(_))) | Ok(None) => (),
+                    Err(error) => {
+                        println!("Failed to run...: {:#?}", error);
+                    },
+                };
+
+                button.set_sensitive(true);
+            });
+        });
 
         // Add this row to our map, so we can easily access it when updating data
         // for a backup.
@@ -286,9 +591,18 @@ impl OverviewUI {
             BackupRow {
                 bytes: bytes_label,
                 files: files_label,
+                progress_bar,
+                run_button,
+                cancel_button,
+                warning,
+                errors: Cell::new(0),
             }
         );
 
+        if let Some(last_run) = last_run {
+            self.rows[&backup.name].show_last_run(last_run);
+        }
+
         row
     }
 }
@@ -1,7 +1,7 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, path::PathBuf, rc::Rc};
 use duplikat_types::*;
 use glib::{prelude::*, error::Error as GError, source::Priority};
-use gio::{prelude::*, SocketClient, DataInputStream, IOStream, OutputStream};
+use gio::{prelude::*, SocketClient, DataInputStream, IOStream, OutputStream, UnixSocketAddress};
 use gtk::prelude::*;
 use crate::Application;
 
@@ -13,17 +13,29 @@ pub(crate) struct Connection {
 
 impl Connection {
     pub(crate) async fn send_message(&self, message: ClientMessage) -> Result<(), GError> {
-        let mut json_message = serde_json::to_string(
-            &message
-        ).unwrap();
+        let request = message.to_http_request();
+        let body = request.body.unwrap_or_default();
 
-        // We need to make sure there is a new line otherwise the other side, which is a
-        // buffered reader which reads line by line, will not process the message.
-        json_message.push('\n');
+        // We speak HTTP/1.0 so that the server closes the connection when it is done
+        // instead of using chunked encoding, leaving us with plain lines of JSON to read.
+        let http_request = format!(
+            "{} {} HTTP/1.0\r\nHost: 127.0.0.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            request.method,
+            request.path,
+            body.len(),
+            body
+        );
 
-        self.ostream.write_all_async_future(json_message, Priority::default()).await
+        self.ostream.write_all_async_future(http_request, Priority::default()).await
             .map_err(|err| err.1)?;
 
+        // Skip the status line and headers, errors are also described in the body.
+        while let Some(line) = self.istream.read_line_utf8_async_future(Priority::default()).await? {
+            if line.trim_end().is_empty() {
+                break;
+            }
+        }
+
         Ok(())
     }
 
@@ -39,6 +51,26 @@ impl Connection {
             })
     }
 
+    /// Like read_message, but for requests that may fail, in which case the
+    /// daemon sends an error in place of the message. Lines that are neither,
+    /// such as messages from a newer daemon, are skipped.
+    pub(crate) async fn read_message_or_error(&self) -> Result<Option<Result<ResticMessage, ServerError>>, GError> {
+        loop {
+            let line = match self.istream.read_line_utf8_async_future(Priority::default()).await? {
+                Some(line) => line.to_string(),
+                None => return Ok(None),
+            };
+
+            if let Ok(message) = serde_json::from_str(&line) {
+                return Ok(Some(Ok(message)));
+            }
+            match serde_json::from_str::<ServerResponse>(&line) {
+                Ok(ServerResponse { error: Some(error), .. }) => return Ok(Some(Err(error))),
+                _ => println!("Skipping unexpected message: {}", line),
+            }
+        }
+    }
+
     pub(crate) async fn read_response(&self) -> Result<ServerResponse, GError> {
         let line = self.istream.read_line_utf8_async_future(Priority::default()).await?;
         Ok(serde_json::from_str(line.unwrap().to_string().as_str()).unwrap())
@@ -50,7 +82,24 @@ pub(crate) struct Server {}
 impl Server {
     pub(crate) async fn connect(application: Rc<RefCell<Application>>) -> Result<Connection, GError> {
         let socket = SocketClient::new();
-        let result = socket.connect_to_host_async_future("127.0.0.1:7667", 7667).await;
+
+        // Prefer the Unix sockets, which let the server know who we are; it will not
+        // talk to us over TCP unless it has been told to.
+        let mut result = None;
+        for path in Self::socket_paths() {
+            if path.exists() {
+                let address = UnixSocketAddress::new(&path);
+                result = Some(socket.connect_async_future(&address).await);
+                if let Some(Ok(_)) = result {
+                    break;
+                }
+            }
+        }
+
+        let result = match result {
+            Some(Ok(socket)) => Ok(socket),
+            _ => socket.connect_to_host_async_future("127.0.0.1:7667", 7667).await,
+        };
 
         let socket = match result {
             Ok(s) => s,
@@ -84,4 +133,11 @@ impl Server {
             istream,
         })
     }
+
+    fn socket_paths() -> Vec<PathBuf> {
+        let mut user_socket = glib::user_runtime_dir();
+        user_socket.push(USER_SOCKET_NAME);
+
+        vec![PathBuf::from(SYSTEM_SOCKET_PATH), user_socket]
+    }
 }
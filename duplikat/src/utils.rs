@@ -1,6 +1,76 @@
+use duplikat_types::ServerError;
+use gtk::prelude::*;
+
 pub(crate) fn next_row_num(num: &mut i32) -> i32 {
     *num += 1;
     *num
 }
 
+pub(crate) fn to_human_readable(bytes: u64) -> String {
+    let tiers = vec!["KiB", "MiB", "GiB", "TiB"];
+    let mut bytes = bytes as f64;
+    for tier in tiers {
+        bytes /= 1024f64;
+        if bytes < 1000f64 {
+            return format!("{:.2} {}", bytes, tier);
+        }
+    }
+    "NaN".to_string()
+}
 
+/// Formats an ISO 8601 time coming from the daemon for the user's locale, or
+/// gives it back as is if it cannot be parsed.
+pub(crate) fn format_time(time: &str) -> String {
+    glib::DateTime::from_iso8601(time, None)
+        .and_then(|time| time.to_local())
+        .and_then(|time| time.format("%c"))
+        .map(|time| time.to_string())
+        .unwrap_or_else(|_| time.to_string())
+}
+
+/// The message for an error from the daemon, along with what can be done
+/// about it where there is something.
+pub(crate) fn explain_error(error: &ServerError) -> String {
+    let advice = match error {
+        ServerError::AlreadyExists { .. } |
+        ServerError::InvalidName { .. } => "Choose a different name.",
+        ServerError::WrongPassword { .. } => "Use the password the repository was created with.",
+        ServerError::RepositoryUnreachable { .. } => "Check the location of the repository and the network connection.",
+        ServerError::BackendAuth { .. } => "Check the key ID and secret.",
+        ServerError::RepositoryLocked { .. } => "Try again once it is done with it.",
+        ServerError::Busy { .. } => "Try again once the backup is done.",
+        ServerError::ResticMissing => "It needs to be installed where duplikatd runs.",
+        ServerError::NotFound { .. } => "It may have been deleted in the meantime.",
+        _ => return error.to_string(),
+    };
+    format!("{} {}", error, advice)
+}
+
+/// Tells the user about an error from the daemon, with whatever restic had to
+/// say about it tucked away.
+pub(crate) async fn show_error(parent: &impl IsA<gtk::Window>, text: &str, error: &ServerError) {
+    let dialog = gtk::MessageDialogBuilder::new()
+        .transient_for(parent)
+        .modal(true)
+        .message_type(gtk::MessageType::Error)
+        .buttons(gtk::ButtonsType::Close)
+        .text(text)
+        .secondary_text(&explain_error(error))
+        .build();
+
+    if let Some(detail) = error.detail().filter(|detail| !detail.is_empty()) {
+        let expander = gtk::Expander::new(Some("Details"));
+        let label = gtk::Label::new(Some(detail));
+        label.set_wrap(true);
+        label.set_selectable(true);
+        label.set_halign(gtk::Align::Start);
+        expander.set_child(Some(&label));
+        dialog.message_area()
+            .downcast::<gtk::Box>()
+            .unwrap()
+            .append(&expander);
+    }
+
+    dialog.run_future().await;
+    dialog.close();
+}
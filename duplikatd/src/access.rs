@@ -0,0 +1,191 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, bail};
+use duplikat_types::ServerError;
+use log::warn;
+use crate::restic::Configuration;
+
+/// Who is on the other side of a connection.
+#[derive(Debug, Clone)]
+pub(crate) enum Peer {
+    /// A client on the Unix socket, identified by its credentials.
+    Local { uid: u32, gid: u32 },
+    /// A client on the TCP socket, which could be anyone.
+    Remote(SocketAddr),
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Peer::Local { uid, .. } => write!(f, "uid {}", uid),
+            Peer::Remote(address) => write!(f, "{}", address),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Rule {
+    User(u32),
+    Group(u32),
+    Tcp,
+}
+
+impl Rule {
+    /// Parses lines such as `user kov`, `user 1000`, `group wheel` or `tcp`.
+    fn parse(line: &str) -> Result<Rule> {
+        let mut parts = line.split_whitespace();
+        let rule = match (parts.next(), parts.next()) {
+            (Some("user"), Some(user)) => match user.parse::<u32>() {
+                Ok(uid) => Rule::User(uid),
+                Err(_) => match users::get_user_by_name(user) {
+                    Some(user) => Rule::User(user.uid()),
+                    None => bail!("No such user: {}", user),
+                },
+            },
+            (Some("group"), Some(group)) => match group.parse::<u32>() {
+                Ok(gid) => Rule::Group(gid),
+                Err(_) => match users::get_group_by_name(group) {
+                    Some(group) => Rule::Group(group.gid()),
+                    None => bail!("No such group: {}", group),
+                },
+            },
+            (Some("tcp"), None) => Rule::Tcp,
+            _ => bail!("Malformed access rule: {}", line),
+        };
+
+        if parts.next().is_some() {
+            bail!("Malformed access rule: {}", line);
+        }
+
+        Ok(rule)
+    }
+}
+
+pub(crate) struct Access {}
+
+impl Access {
+    /// Root and the user the daemon runs as are always allowed in through
+    /// the Unix socket; anyone else needs to be listed in the access file.
+    pub(crate) fn allows(peer: &Peer) -> bool {
+        if Self::is_privileged(peer) {
+            return true;
+        }
+
+        let rules = Self::rules();
+        match peer {
+            Peer::Remote(_) => rules.contains(&Rule::Tcp),
+            Peer::Local { uid, gid } => {
+                let mut groups = vec![*gid];
+                if let Some(user_groups) = users::get_user_by_uid(*uid).and_then(|user| user.groups()) {
+                    groups.extend(user_groups.iter().map(|group| group.gid()));
+                }
+
+                rules.iter().any(|rule| match rule {
+                    Rule::User(allowed) => allowed == uid,
+                    Rule::Group(allowed) => groups.contains(allowed),
+                    Rule::Tcp => false,
+                })
+            },
+        }
+    }
+
+    /// Whether the peer could read the configuration directly anyway, which
+    /// is what it takes to be told the secrets of a backup.
+    pub(crate) fn is_privileged(peer: &Peer) -> bool {
+        match peer {
+            Peer::Local { uid, .. } => *uid == 0 || *uid == users::get_effective_uid(),
+            Peer::Remote(_) => false,
+        }
+    }
+
+    /// Where restic may restore files for the peer. The daemon usually runs
+    /// as root, so only privileged peers get to pick any place; everyone else
+    /// has to name an existing directory they own, which is resolved here so
+    /// restic does not follow links out of it.
+    pub(crate) fn restore_target(peer: &Peer, target: &Path) -> Result<PathBuf, ServerError> {
+        if !target.is_absolute() {
+            return Err(ServerError::BadRequest {
+                detail: format!("The restore target {} is not an absolute path", target.display()),
+            });
+        }
+        if Self::is_privileged(peer) {
+            return Ok(target.to_path_buf());
+        }
+
+        let denied = || ServerError::PermissionDenied {
+            detail: format!("{} may only restore into existing directories it owns", peer),
+        };
+        let uid = match peer {
+            Peer::Local { uid, .. } => *uid,
+            Peer::Remote(_) => return Err(denied()),
+        };
+        let target = std::fs::canonicalize(target).map_err(|_| denied())?;
+        match std::fs::metadata(&target) {
+            Ok(metadata) if metadata.is_dir() && metadata.uid() == uid => Ok(target),
+            _ => Err(denied()),
+        }
+    }
+
+    // Read every time, so changes apply without restarting the daemon.
+    fn rules() -> Vec<Rule> {
+        let contents = match std::fs::read_to_string(Configuration::access_file()) {
+            Ok(contents) => contents,
+            Err(_) => return vec![],
+        };
+
+        contents.lines()
+            .map(|line| line.split('#').next().unwrap().trim())
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| Rule::parse(line)
+                .map_err(|error| warn!("{}", error))
+                .ok()
+            )
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rule_parsing() {
+        assert_eq!(Rule::parse("user 1000").unwrap(), Rule::User(1000));
+        assert_eq!(Rule::parse("group  10").unwrap(), Rule::Group(10));
+        assert_eq!(Rule::parse("tcp").unwrap(), Rule::Tcp);
+        assert!(Rule::parse("tcp 1000").is_err());
+        assert!(Rule::parse("user").is_err());
+        assert!(Rule::parse("everyone").is_err());
+    }
+
+    #[test]
+    fn restore_targets() {
+        let dir = std::env::temp_dir().join(format!("duplikatd-restore-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let uid = std::fs::metadata(&dir).unwrap().uid();
+        let owner = Peer::Local { uid, gid: 0 };
+        let stranger = Peer::Local { uid: uid + 1, gid: 0 };
+
+        assert_eq!(Access::restore_target(&owner, &dir).unwrap(), std::fs::canonicalize(&dir).unwrap());
+        assert!(matches!(
+            Access::restore_target(&owner, Path::new("restored")),
+            Err(ServerError::BadRequest { .. })
+        ));
+        assert!(matches!(
+            Access::restore_target(&stranger, &dir),
+            Err(ServerError::PermissionDenied { .. })
+        ));
+        assert!(matches!(
+            Access::restore_target(&stranger, Path::new("/etc")),
+            Err(ServerError::PermissionDenied { .. })
+        ));
+        assert!(matches!(
+            Access::restore_target(&Peer::Remote(SocketAddr::from(([127, 0, 0, 1], 7667))), &dir),
+            Err(ServerError::PermissionDenied { .. })
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
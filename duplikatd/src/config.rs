@@ -0,0 +1,559 @@
+use std::fs::OpenOptions;
+use std::net::SocketAddr;
+use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use anyhow::{Context, Result, bail};
+use duplikat_types::*;
+use log::{LevelFilter, info, warn};
+use serde::{Serialize, Deserialize};
+use crate::runner::DEFAULT_MAX_PROCESSES;
+use crate::scheduler::Scheduler;
+use crate::secrets::SecretStoreKind;
+
+/// Bumped whenever the layout of the configuration files changes, so older
+/// ones can be told apart and upgraded as they are read.
+pub(crate) const SCHEMA_VERSION: u32 = 1;
+
+pub(crate) const BACKUP_FILE: &str = "backup.json";
+pub(crate) const DAEMON_FILE: &str = "duplikatd.json";
+
+// Before there were configuration documents, each setting had a file of its own.
+const LEGACY_BACKUP_FILES: &[&str] = &["repo", "include", "exclude", "schedule", "retention", "rclone"];
+const LEGACY_DAEMON_FILES: &[&str] = &["max-processes", "secret-store"];
+
+static CONFIG: OnceLock<DaemonConfig> = OnceLock::new();
+
+#[derive(Deserialize)]
+struct Versioned {
+    #[serde(default)]
+    version: u32,
+}
+
+/// Everything about a backup but its secrets, which are up to the secret
+/// store. Kept in the backup's directory, along with its history.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct BackupDocument {
+    pub version: u32,
+    pub repository: Repository,
+    #[serde(default)]
+    pub include: Vec<PathBuf>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<Schedule>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<RetentionPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rclone: Option<RcloneOptions>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_cert: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<PathBuf>,
+}
+
+impl BackupDocument {
+    pub(crate) fn from_backup(backup: &Backup) -> Self {
+        BackupDocument {
+            version: SCHEMA_VERSION,
+            repository: backup.repository.clone(),
+            include: backup.include.clone(),
+            exclude: backup.exclude.clone(),
+            schedule: backup.schedule.clone(),
+            retention: backup.retention.clone(),
+            rclone: match backup.repository.kind {
+                RepositoryKind::Rclone => backup.rclone.clone(),
+                _ => None,
+            },
+            ca_cert: backup.ca_cert.clone(),
+            client_cert: backup.client_cert.clone(),
+        }
+    }
+
+    /// Reads the document in the given backup directory, moving the older
+    /// one-file-per-setting layout over to it if that is what is there.
+    pub(crate) fn load(dir: &Path) -> Result<Self> {
+        let document = Self::read(dir)?;
+        if !dir.join(BACKUP_FILE).exists() {
+            document.save(dir)?;
+            for file in LEGACY_BACKUP_FILES {
+                remove_if_exists(&dir.join(file))?;
+            }
+            info!("Moved the configuration in {} to {}", dir.display(), BACKUP_FILE);
+        }
+
+        Ok(document)
+    }
+
+    /// Like `load`, but leaves older layouts as they are.
+    pub(crate) fn read(dir: &Path) -> Result<Self> {
+        let path = dir.join(BACKUP_FILE);
+        if !path.exists() && dir.join("repo").exists() {
+            return Self::from_legacy(dir);
+        }
+
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        let document: Self = parse_versioned(&contents)
+            .with_context(|| format!("Bad configuration in {}", path.display()))?;
+        document.validate()
+            .with_context(|| format!("Bad configuration in {}", path.display()))?;
+
+        Ok(document)
+    }
+
+    pub(crate) fn save(&self, dir: &Path) -> Result<()> {
+        let contents = serde_json::to_string_pretty(self)? + "\n";
+        write_atomically(&dir.join(BACKUP_FILE), contents.as_bytes(), 0o644)
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.repository.validate()?;
+        if let Some(schedule) = &self.schedule {
+            Scheduler::validate(schedule)?;
+        }
+        Ok(())
+    }
+
+    fn from_legacy(dir: &Path) -> Result<Self> {
+        let read = |file: &str| -> Result<Option<String>> {
+            match std::fs::read_to_string(dir.join(file)) {
+                Ok(contents) => Ok(Some(contents)),
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(error) => Err(error).with_context(|| format!("Could not read {}", dir.join(file).display())),
+            }
+        };
+        let lines = |contents: Option<String>| -> Vec<String> {
+            contents.unwrap_or_default()
+                .lines()
+                .map(|line| line.trim().to_string())
+                .filter(|line| !line.is_empty())
+                .collect()
+        };
+        let json = |file: &str| -> Result<Option<serde_json::Value>> {
+            read(file)?
+                .map(|contents| serde_json::from_str(&contents))
+                .transpose()
+                .with_context(|| format!("Bad {} in {}", file, dir.display()))
+        };
+
+        // Editors like to end files with a newline, which is not part of the repository.
+        let repository = read("repo")?.unwrap_or_default()
+            .trim_end_matches('\n')
+            .parse::<Repository>()
+            .with_context(|| format!("Bad repository in {}", dir.display()))?;
+
+        Ok(BackupDocument {
+            version: SCHEMA_VERSION,
+            repository,
+            include: lines(read("include")?).into_iter().map(PathBuf::from).collect(),
+            exclude: lines(read("exclude")?),
+            schedule: json("schedule")?.map(serde_json::from_value).transpose()?,
+            retention: json("retention")?.map(serde_json::from_value).transpose()?,
+            rclone: json("rclone")?.map(serde_json::from_value).transpose()?,
+            // These were kept in the environment, and are read from there
+            // until the backup is next written.
+            ca_cert: None,
+            client_cert: None,
+        })
+    }
+}
+
+/// Settings for the daemon itself, in `duplikatd.json`, which the command
+/// line can override. Who may talk to it is still up to the `access` file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub(crate) struct DaemonConfig {
+    pub version: u32,
+    /// Where `access` and the backups are; `/etc/duplikatd` for the system
+    /// daemon and the user's configuration directory otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config_dir: Option<PathBuf>,
+    /// Where the history of runs is kept; the configuration directory
+    /// unless set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_dir: Option<PathBuf>,
+    /// Where restic keeps its cache, if not where it would by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_dir: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub socket_path: Option<PathBuf>,
+    /// Where to listen for TCP connections, `null` to not listen at all.
+    pub tcp_address: Option<SocketAddr>,
+    pub restic: PathBuf,
+    /// The rclone restic runs for rclone repositories, passed as
+    /// `-o rclone.program`; restic's default if not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rclone_program: Option<PathBuf>,
+    /// Passed as `-o rclone.args`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rclone_args: Option<String>,
+    pub log_level: LevelFilter,
+    /// How many restic processes may run at the same time.
+    pub max_processes: usize,
+    pub secret_store: SecretStoreKind,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            version: SCHEMA_VERSION,
+            config_dir: None,
+            state_dir: None,
+            cache_dir: None,
+            socket_path: None,
+            tcp_address: Some(SocketAddr::from(([127, 0, 0, 1], 7667))),
+            restic: PathBuf::from("restic"),
+            rclone_program: None,
+            rclone_args: None,
+            log_level: LevelFilter::Info,
+            max_processes: DEFAULT_MAX_PROCESSES,
+            secret_store: SecretStoreKind::default(),
+        }
+    }
+}
+
+impl DaemonConfig {
+    /// Must be called before the configuration is first used to have any
+    /// effect.
+    pub(crate) fn set(config: DaemonConfig) {
+        let _ = CONFIG.set(config);
+    }
+
+    pub(crate) fn get() -> &'static DaemonConfig {
+        CONFIG.get_or_init(DaemonConfig::default)
+    }
+
+    /// Where the configuration lives when nobody says otherwise.
+    pub(crate) fn default_config_dir() -> PathBuf {
+        let mut path = match users::get_effective_uid() {
+            0 => PathBuf::from("/etc"),
+            _ => dirs::config_dir().unwrap_or_else(|| PathBuf::from(".")),
+        };
+        path.push("duplikatd");
+        path
+    }
+
+    pub(crate) fn config_dir(&self) -> PathBuf {
+        self.config_dir.clone().unwrap_or_else(Self::default_config_dir)
+    }
+
+    pub(crate) fn state_dir(&self) -> PathBuf {
+        self.state_dir.clone().unwrap_or_else(|| self.config_dir())
+    }
+
+    /// The system daemon uses a well known location, per-user daemons use the
+    /// user's runtime directory, if there is one.
+    pub(crate) fn socket_path(&self) -> Option<PathBuf> {
+        if let Some(path) = &self.socket_path {
+            return Some(path.clone());
+        }
+
+        match users::get_effective_uid() {
+            0 => Some(PathBuf::from(SYSTEM_SOCKET_PATH)),
+            _ => dirs::runtime_dir().map(|mut path| {
+                path.push(USER_SOCKET_NAME);
+                path
+            }),
+        }
+    }
+
+    /// Reads the configuration file. If there is none, but the settings of
+    /// older versions are next to it, they are moved into one.
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let config = Self::read(path)?;
+
+        if let Some(dir) = Self::legacy_dir(path) {
+            config.save(path)?;
+            for file in LEGACY_DAEMON_FILES {
+                remove_if_exists(&dir.join(file))?;
+            }
+            info!("Moved the daemon configuration in {} to {}", dir.display(), path.display());
+        }
+
+        Ok(config)
+    }
+
+    /// Like `load`, but leaves the settings of older versions as they are.
+    pub(crate) fn read(path: &Path) -> Result<Self> {
+        if path.exists() {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("Could not read {}", path.display()))?;
+            return parse_versioned(&contents)
+                .with_context(|| format!("Bad configuration in {}", path.display()));
+        }
+
+        let dir = match Self::legacy_dir(path) {
+            Some(dir) => dir,
+            None => return Ok(DaemonConfig::default()),
+        };
+
+        let mut config = DaemonConfig::default();
+        if let Ok(contents) = std::fs::read_to_string(dir.join("max-processes")) {
+            match contents.trim().parse() {
+                Ok(max) => config.max_processes = max,
+                Err(_) => warn!("Malformed max-processes file, using {}", DEFAULT_MAX_PROCESSES),
+            }
+        }
+        if let Ok(contents) = std::fs::read_to_string(dir.join("secret-store")) {
+            match serde_json::from_value(serde_json::Value::String(contents.trim().to_string())) {
+                Ok(store) => config.secret_store = store,
+                Err(_) => warn!("Unknown secret store {:?}, keeping secrets in files", contents.trim()),
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Where the settings of older versions are, if there is no configuration
+    /// file yet to take their place.
+    fn legacy_dir(path: &Path) -> Option<&Path> {
+        if path.exists() {
+            return None;
+        }
+        path.parent().filter(|dir| LEGACY_DAEMON_FILES.iter().any(|file| dir.join(file).exists()))
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let contents = serde_json::to_string_pretty(self)? + "\n";
+        write_atomically(path, contents.as_bytes(), 0o644)
+    }
+
+    /// Looks for anything that would keep the daemon or its backups from
+    /// working, without changing anything.
+    pub(crate) fn check(&self) -> Vec<String> {
+        let mut problems = vec![];
+
+        if self.max_processes == 0 {
+            problems.push("max_processes must be at least 1".to_string());
+        }
+
+        let directories = [
+            ("config_dir", Some(self.config_dir())),
+            ("state_dir", Some(self.state_dir())),
+            ("cache_dir", self.cache_dir.clone()),
+        ];
+        for (setting, dir) in directories.iter() {
+            if let Some(dir) = dir {
+                if dir.exists() && !dir.is_dir() {
+                    problems.push(format!("{} {} is not a directory", setting, dir.display()));
+                }
+            }
+        }
+
+        match std::process::Command::new(&self.restic).arg("version").output() {
+            Ok(output) if output.status.success() => (),
+            Ok(output) => problems.push(format!(
+                "{} version failed: {}", self.restic.display(), String::from_utf8_lossy(&output.stderr).trim()
+            )),
+            Err(error) => problems.push(format!("{} could not be run: {}", self.restic.display(), error)),
+        }
+
+        let backups = self.config_dir().join("backups");
+        if let Ok(entries) = std::fs::read_dir(&backups) {
+            for entry in entries.flatten() {
+                if let Err(error) = BackupDocument::read(&entry.path()) {
+                    problems.push(format!("{}: {:#}", entry.file_name().to_string_lossy(), error));
+                }
+            }
+        }
+
+        problems
+    }
+}
+
+/// Parses a configuration document, refusing those written by a newer
+/// version of the daemon, which may mean something else by them.
+fn parse_versioned<T: serde::de::DeserializeOwned>(contents: &str) -> Result<T> {
+    let Versioned { version } = serde_json::from_str(contents)?;
+    if version > SCHEMA_VERSION {
+        bail!("Version {} is newer than the {} this daemon knows", version, SCHEMA_VERSION);
+    }
+
+    // Older versions are to be upgraded here, once there are any.
+    Ok(serde_json::from_str(contents)?)
+}
+
+/// Writes to a temporary file first and moves it in place, so readers see
+/// either the old contents or the new ones, never a mix.
+pub(crate) fn write_atomically(path: &Path, contents: &[u8], mode: u32) -> Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".new");
+    let temporary = PathBuf::from(temporary);
+
+    remove_if_exists(&temporary)?;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&temporary)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&temporary, path)?;
+
+    Ok(())
+}
+
+pub(crate) fn remove_if_exists(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temporary_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("duplikatd-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn backup_migration() {
+        let dir = temporary_dir("backup-migration");
+        std::fs::write(dir.join("repo"), "sftp:backup@nas:/srv/restic\n").unwrap();
+        std::fs::write(dir.join("include"), "/home\n/etc\n").unwrap();
+        std::fs::write(dir.join("exclude"), "").unwrap();
+        std::fs::write(dir.join("schedule"), r#"{"kind":"calendar","expression":"daily 02:00"}"#).unwrap();
+        std::fs::write(dir.join("password"), "hunter2").unwrap();
+
+        let document = BackupDocument::load(&dir).unwrap();
+        assert_eq!(document.repository.to_string(), "sftp:backup@nas:/srv/restic");
+        assert_eq!(document.include, vec![PathBuf::from("/home"), PathBuf::from("/etc")]);
+        assert!(document.exclude.is_empty());
+        assert_eq!(document.schedule, Some(Schedule::Calendar { expression: "daily 02:00".to_string() }));
+        assert_eq!(document.retention, None);
+
+        // The secrets are left for the secret store to deal with.
+        assert!(!dir.join("repo").exists());
+        assert!(!dir.join("schedule").exists());
+        assert!(dir.join("password").exists());
+        assert_eq!(BackupDocument::load(&dir).unwrap(), document);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backup_validation() {
+        let dir = temporary_dir("backup-validation");
+        let write = |contents: serde_json::Value| {
+            std::fs::write(dir.join(BACKUP_FILE), contents.to_string()).unwrap();
+        };
+
+        write(serde_json::json!({
+            "version": 1,
+            "repository": {"kind": "local", "identifier": "", "path": "/srv/restic"},
+        }));
+        let document = BackupDocument::load(&dir).unwrap();
+        assert!(document.include.is_empty());
+        assert_eq!(document.schedule, None);
+
+        // Which rclone runs is up to the daemon, not the backup.
+        write(serde_json::json!({
+            "version": 1,
+            "repository": {"kind": "rclone", "identifier": "gdrive", "path": "backups"},
+            "rclone": {"config": "/etc/rclone.conf", "program": "/tmp/evil", "args": "-x"},
+        }));
+        let document = BackupDocument::load(&dir).unwrap();
+        assert_eq!(
+            serde_json::to_value(&document.rclone).unwrap(),
+            serde_json::json!({"config": "/etc/rclone.conf"})
+        );
+
+        write(serde_json::json!({
+            "version": 1,
+            "repository": {"kind": "sftp", "identifier": "", "path": "/srv/restic"},
+        }));
+        assert!(BackupDocument::load(&dir).is_err());
+
+        write(serde_json::json!({
+            "version": 1,
+            "repository": {"kind": "local", "identifier": "", "path": "/srv/restic"},
+            "schedule": {"kind": "interval", "seconds": 0},
+        }));
+        assert!(BackupDocument::load(&dir).is_err());
+
+        write(serde_json::json!({
+            "version": SCHEMA_VERSION + 1,
+            "repository": {"kind": "local", "identifier": "", "path": "/srv/restic"},
+        }));
+        let error = BackupDocument::load(&dir).unwrap_err();
+        assert!(format!("{:#}", error).contains("newer"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn daemon_migration() {
+        let dir = temporary_dir("daemon-migration");
+        let path = dir.join(DAEMON_FILE);
+        assert_eq!(DaemonConfig::load(&path).unwrap(), DaemonConfig::default());
+        assert!(!path.exists());
+
+        std::fs::write(dir.join("max-processes"), "2\n").unwrap();
+        std::fs::write(dir.join("secret-store"), "secret-service\n").unwrap();
+        assert_eq!(DaemonConfig::read(&path).unwrap().max_processes, 2);
+        assert!(!path.exists());
+        assert!(dir.join("max-processes").exists());
+
+        let config = DaemonConfig::load(&path).unwrap();
+        assert_eq!(config.max_processes, 2);
+        assert_eq!(config.secret_store, SecretStoreKind::SecretService);
+        assert!(!dir.join("max-processes").exists());
+        assert_eq!(DaemonConfig::load(&path).unwrap(), config);
+
+        let contents = r#"{"max_processes": 8, "tcp_address": null, "log_level": "debug"}"#;
+        std::fs::write(&path, contents).unwrap();
+        std::fs::write(dir.join("max-processes"), "3\n").unwrap();
+        let config = DaemonConfig::read(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), contents);
+        assert!(dir.join("max-processes").exists());
+        assert_eq!(DaemonConfig::load(&path).unwrap(), config);
+        assert_eq!(config.max_processes, 8);
+        assert_eq!(config.tcp_address, None);
+        assert_eq!(config.log_level, LevelFilter::Debug);
+        assert_eq!(config.secret_store, SecretStoreKind::File);
+        assert_eq!(config.restic, PathBuf::from("restic"));
+        assert_eq!(config.state_dir(), config.config_dir());
+
+        std::fs::write(&path, r#"{"version": 1, "max_processes": "many"}"#).unwrap();
+        assert!(DaemonConfig::load(&path).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn daemon_check() {
+        let dir = temporary_dir("daemon-check");
+        std::fs::create_dir_all(dir.join("backups/home")).unwrap();
+        std::fs::write(dir.join("backups/home/repo"), "sftp:nas\n").unwrap();
+        std::fs::write(dir.join("cache"), "").unwrap();
+
+        let config = DaemonConfig {
+            config_dir: Some(dir.clone()),
+            cache_dir: Some(dir.join("cache")),
+            restic: dir.join("no-restic"),
+            max_processes: 0,
+            ..DaemonConfig::default()
+        };
+        let problems = config.check();
+        assert_eq!(problems.len(), 4, "{:#?}", problems);
+        assert!(problems[0].contains("max_processes"));
+        assert!(problems[1].contains("cache_dir"));
+        assert!(problems[2].contains("could not be run"));
+        assert!(problems[3].starts_with("home: Bad repository"));
+
+        // Checking does not move anything around.
+        assert!(dir.join("backups/home/repo").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,116 @@
+use std::fs::{File, OpenOptions};
+use std::io::{prelude::*, BufReader};
+use anyhow::Result;
+use duplikat_types::*;
+use log::warn;
+use tokio::io::AsyncWrite;
+use crate::restic::{Configuration, send_error, send_message};
+use crate::runner::server_error;
+
+// Older runs are dropped once a backup has this many in its history.
+const MAX_RUNS: usize = 500;
+
+// How many of the files restic could not back up are kept with a run.
+pub(crate) const MAX_ERRORS: usize = 100;
+
+// Restic can get very chatty on stderr when it cannot read files, so only the
+// end of it is kept.
+const MAX_STDERR: usize = 16 * 1024;
+
+pub(crate) struct History {}
+
+impl History {
+    pub(crate) async fn get_run_history<W: AsyncWrite + Unpin>(request: &ClientMessageGetRunHistory, writer: &mut W) {
+        match Self::runs_for(&request.name, request.limit) {
+            Ok(runs) => {
+                let message = ResticMessage::RunHistory(
+                    ResticMessageRunHistory {
+                        name: request.name.clone(),
+                        runs,
+                    }
+                );
+                send_message(&message, writer).await;
+            },
+            Err(error) => {
+                send_error(&server_error(&error, Operation::GetRunHistory), writer).await;
+            },
+        }
+    }
+
+    /// Appends a finished run to the history of the given backup.
+    pub(crate) fn record(name: &str, record: &RunRecord) -> Result<()> {
+        let path = Configuration::history_file(name);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        writeln!(file, "{}", serde_json::to_string(record)?)?;
+        drop(file);
+
+        let lines = Self::lines(name)?;
+        if lines.len() > MAX_RUNS {
+            let temporary = path.with_extension("new");
+            std::fs::write(&temporary, lines[lines.len() - MAX_RUNS..].join("\n") + "\n")?;
+            std::fs::rename(&temporary, &path)?;
+        }
+
+        Ok(())
+    }
+
+    /// The most recent runs of the given backup, newest first.
+    pub(crate) fn runs_for(name: &str, limit: Option<usize>) -> Result<Vec<RunRecord>> {
+        Configuration::ensure_exists(name)?;
+
+        let lines = Self::lines(name)?;
+        let limit = limit.unwrap_or(lines.len());
+        Ok(lines.iter()
+            .rev()
+            .filter_map(|line| serde_json::from_str(line)
+                .map_err(|error| warn!("Bad run history entry for {}: {:#?}", name, error))
+                .ok()
+            )
+            .take(limit)
+            .collect())
+    }
+
+    fn lines(name: &str) -> Result<Vec<String>> {
+        match File::open(Configuration::history_file(name)) {
+            Ok(file) => Ok(BufReader::new(file).lines().collect::<std::io::Result<_>>()?),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+            Err(error) => Err(error.into()),
+        }
+    }
+}
+
+/// Keeps the end of what restic wrote to stderr, which is where the reason
+/// for a failure usually is.
+pub(crate) fn truncate_stderr(stderr: &str) -> String {
+    if stderr.len() <= MAX_STDERR {
+        return stderr.to_string();
+    }
+
+    let mut start = stderr.len() - MAX_STDERR;
+    while !stderr.is_char_boundary(start) {
+        start += 1;
+    }
+    format!("[...]\n{}", &stderr[start..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stderr_truncation() {
+        assert_eq!(truncate_stderr("Fatal: wrong password"), "Fatal: wrong password");
+
+        let long = "ação\n".repeat(MAX_STDERR);
+        let truncated = truncate_stderr(&long);
+        assert!(truncated.starts_with("[...]\n"));
+        assert!(truncated.len() <= MAX_STDERR + "[...]\n".len());
+        assert!(truncated.ends_with("ação\n"));
+    }
+}
@@ -0,0 +1,183 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use anyhow::Result;
+use duplikat_types::*;
+use hyper::{Body, Request, Response, Server, StatusCode};
+use hyper::header::CONTENT_TYPE;
+use hyper::server::{accept, conn::AddrStream};
+use hyper::service::{make_service_fn, service_fn};
+use log::info;
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use crate::access::{Access, Peer};
+use crate::process_request;
+
+// How much output from a handler we buffer while the client is slow to read.
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+
+const NDJSON: &str = "application/x-ndjson";
+
+pub(crate) async fn serve_tcp(address: SocketAddr) -> Result<()> {
+    let make_service = make_service_fn(|connection: &AddrStream| {
+        let peer = Peer::Remote(connection.remote_addr());
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| handle(peer.clone(), request)))
+        }
+    });
+
+    Server::bind(&address).serve(make_service).await?;
+    Ok(())
+}
+
+pub(crate) async fn serve_unix(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    // A socket left behind by a previous instance would make binding fail.
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+
+    // Anyone may connect, each request is authorized based on the credentials
+    // of the process on the other side.
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o666))?;
+
+    let incoming = futures::stream::unfold(listener, |listener| async move {
+        let connection = listener.accept().await.map(|(stream, _)| stream);
+        Some((connection, listener))
+    });
+
+    let make_service = make_service_fn(|connection: &UnixStream| {
+        let peer = connection.peer_cred()
+            .map(|credentials| Peer::Local { uid: credentials.uid(), gid: credentials.gid() });
+        async move {
+            let peer = peer?;
+            Ok::<_, std::io::Error>(service_fn(move |request| handle(peer.clone(), request)))
+        }
+    });
+
+    Server::builder(accept::from_stream(incoming)).serve(make_service).await?;
+    Ok(())
+}
+
+fn status_for(error: &ServerError) -> StatusCode {
+    match error {
+        ServerError::NotFound { .. } => StatusCode::NOT_FOUND,
+        ServerError::AlreadyExists { .. } |
+        ServerError::Busy { .. } |
+        ServerError::RepositoryLocked { .. } => StatusCode::CONFLICT,
+        ServerError::InvalidName { .. } |
+        ServerError::BadRequest { .. } => StatusCode::BAD_REQUEST,
+        ServerError::WrongPassword { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+        ServerError::PermissionDenied { .. } => StatusCode::FORBIDDEN,
+        ServerError::RepositoryUnreachable { .. } |
+        ServerError::BackendAuth { .. } => StatusCode::BAD_GATEWAY,
+        ServerError::ResticMissing |
+        ServerError::IncompleteSnapshot { .. } |
+        ServerError::Failed { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn error_response(status: StatusCode, error: ServerError) -> Response<Body> {
+    let body = json!({ "error": error, "message": error.to_string() }).to_string() + "\n";
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, NDJSON)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// Maps the request to a client message and streams whatever its handler
+/// writes back as newline-delimited JSON. The first line decides the status
+/// code, so failures that happen right away are reported as such.
+async fn handle(peer: Peer, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if !Access::allows(&peer) {
+        return Ok(error_response(
+            StatusCode::FORBIDDEN,
+            ServerError::PermissionDenied { detail: format!("{} is not allowed to use duplikatd", peer) }
+        ));
+    }
+
+    let method = request.method().to_string();
+    let path = request.uri()
+        .path_and_query()
+        .map(|path| path.to_string())
+        .unwrap_or_default();
+
+    let body = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(body) => body,
+        Err(error) => return Ok(error_response(
+            StatusCode::BAD_REQUEST, ServerError::BadRequest { detail: error.to_string() }
+        )),
+    };
+
+    let mut message = match ClientMessage::from_http_request(&method, &path, &body) {
+        Ok(message) => message,
+        Err(error @ HttpRequestError::NotFound(..)) => return Ok(error_response(
+            StatusCode::NOT_FOUND, ServerError::BadRequest { detail: error.to_string() }
+        )),
+        Err(error) => return Ok(error_response(
+            StatusCode::BAD_REQUEST, ServerError::BadRequest { detail: error.to_string() }
+        )),
+    };
+
+    if let ClientMessage::RevealSecret(reveal) = &message {
+        if !Access::is_privileged(&peer) {
+            return Ok(error_response(
+                StatusCode::FORBIDDEN,
+                ServerError::PermissionDenied { detail: format!("{} may not read the secrets of backups", peer) }
+            ));
+        }
+        info!("Revealing the secrets of {} to {}", reveal.name, peer);
+    }
+
+    if let ClientMessage::RestoreSnapshot(restore) = &mut message {
+        match Access::restore_target(&peer, &restore.target) {
+            Ok(target) => restore.target = target,
+            Err(error) => return Ok(error_response(status_for(&error), error)),
+        }
+    }
+
+    // The handler keeps going even if the client goes away: writes fail once
+    // the reader is dropped, and the helpers it writes with ignore that.
+    let (reader, mut writer) = tokio::io::duplex(STREAM_BUFFER_SIZE);
+    tokio::spawn(async move {
+        process_request(message, &mut writer).await;
+    });
+
+    let mut lines = BufReader::new(reader).lines();
+    let first = match lines.next_line().await {
+        Ok(Some(line)) => line,
+        _ => return Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap()),
+    };
+
+    let status = serde_json::from_str::<ServerResponse>(&first)
+        .ok()
+        .and_then(|response| response.error)
+        .map(|error| status_for(&error))
+        .unwrap_or(StatusCode::OK);
+
+    let stream = futures::stream::unfold((Some(first), lines), |(first, mut lines)| async move {
+        let line = match first {
+            Some(line) => line,
+            None => match lines.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => return None,
+                Err(error) => return Some((Err(error), (None, lines))),
+            },
+        };
+        Some((Ok(line + "\n"), (None, lines)))
+    });
+
+    Ok(Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, NDJSON)
+        .body(Body::wrap_stream(stream))
+        .unwrap())
+}
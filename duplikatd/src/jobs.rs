@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use anyhow::{Result, bail};
+use duplikat_types::*;
+use log::warn;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+// How long restic gets to clean up its lock after being interrupted before
+// we kill it for good.
+const CANCEL_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+// How far behind a slow subscriber may fall before it starts missing events.
+const EVENT_BUFFER: usize = 256;
+
+struct Job {
+    // Not there yet while waiting for other restic processes to finish.
+    pid: Option<u32>,
+    cancelled: bool,
+    // So that clients who subscribe halfway through know where we are.
+    last_status: Option<String>,
+}
+
+// Running or queued restic processes, keyed by the name of the backup they
+// belong to.
+static JOBS: Mutex<BTreeMap<String, Job>> = Mutex::new(BTreeMap::new());
+
+static EVENTS: OnceLock<broadcast::Sender<Event>> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum EventKind {
+    Status,
+    Message,
+    /// The last event of a run.
+    Finished,
+}
+
+/// A line of JSON about one of the backups, as sent to clients.
+#[derive(Clone, Debug)]
+pub(crate) struct Event {
+    pub name: String,
+    pub kind: EventKind,
+    pub line: String,
+}
+
+fn events() -> &'static broadcast::Sender<Event> {
+    EVENTS.get_or_init(|| broadcast::channel(EVENT_BUFFER).0)
+}
+
+pub(crate) struct Jobs {}
+
+impl Jobs {
+    /// Claims the given backup, before restic is started for it.
+    pub(crate) fn register(name: &str) -> Result<()> {
+        let mut jobs = JOBS.lock().unwrap();
+        if jobs.contains_key(name) {
+            bail!(ServerError::Busy { name: name.to_string() });
+        }
+
+        jobs.insert(name.to_string(), Job { pid: None, cancelled: false, last_status: None });
+        Ok(())
+    }
+
+    /// Whether the job was cancelled before restic got to start.
+    pub(crate) fn is_cancelled(name: &str) -> bool {
+        JOBS.lock().unwrap()
+            .get(name)
+            .map(|job| job.cancelled)
+            .unwrap_or(false)
+    }
+
+    /// Records the restic process doing the job, returning false if it was
+    /// cancelled in the meantime, in which case the process should not be
+    /// allowed to go on.
+    pub(crate) fn started(name: &str, pid: u32) -> bool {
+        match JOBS.lock().unwrap().get_mut(name) {
+            Some(job) if !job.cancelled => {
+                job.pid.replace(pid);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Forgets about the job for the given backup, returning whether it had
+    /// been cancelled.
+    pub(crate) fn unregister(name: &str) -> bool {
+        JOBS.lock().unwrap()
+            .remove(name)
+            .map(|job| job.cancelled)
+            .unwrap_or(false)
+    }
+
+    pub(crate) fn is_running(name: &str) -> bool {
+        JOBS.lock().unwrap().contains_key(name)
+    }
+
+    /// Asks restic to stop with SIGINT, so it gets a chance to remove its lock
+    /// from the repository, then kills it if it is still around after a grace
+    /// period. Jobs still waiting for their turn simply never start.
+    pub(crate) fn cancel(name: &str) -> Result<()> {
+        let pid = {
+            let mut jobs = JOBS.lock().unwrap();
+            let job = match jobs.get_mut(name) {
+                Some(job) => job,
+                None => bail!("Backup {} is not running", name),
+            };
+            job.cancelled = true;
+            match job.pid {
+                Some(pid) => pid,
+                None => return Ok(()),
+            }
+        };
+
+        Self::signal(pid, libc::SIGINT)?;
+
+        let name = name.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep(CANCEL_GRACE_PERIOD).await;
+
+            // The process is only reaped after it is unregistered, so as long
+            // as the same job is still there the pid cannot have been reused.
+            let still_running = JOBS.lock().unwrap()
+                .get(&name)
+                .map(|job| job.pid == Some(pid))
+                .unwrap_or(false);
+
+            if still_running {
+                warn!("restic did not stop for backup {}, killing it", name);
+                if let Err(error) = Self::signal(pid, libc::SIGKILL) {
+                    warn!("{:#?}", error);
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Lets everyone following the backups know about something that happened
+    /// to one of them.
+    pub(crate) fn publish(name: &str, kind: EventKind, line: String) {
+        // Holding the lock while sending means subscribers see either the status
+        // kept here or the event, never neither.
+        let mut jobs = JOBS.lock().unwrap();
+        if kind == EventKind::Status {
+            if let Some(job) = jobs.get_mut(name) {
+                job.last_status.replace(line.clone());
+            }
+        }
+
+        // Not having anyone listening is fine.
+        let _ = events().send(Event { name: name.to_string(), kind, line });
+    }
+
+    /// The backups running right now with their latest status, and a receiver
+    /// for whatever happens from then on.
+    pub(crate) fn subscribe() -> (Vec<(String, Option<String>)>, broadcast::Receiver<Event>) {
+        let jobs = JOBS.lock().unwrap();
+        let running = jobs.iter()
+            .map(|(name, job)| (name.clone(), job.last_status.clone()))
+            .collect();
+        (running, events().subscribe())
+    }
+
+    pub(crate) async fn subscribe_events<W: AsyncWrite + Unpin>(writer: &mut W) {
+        let (running, events) = Self::subscribe();
+
+        let mut lines = vec![
+            serde_json::to_string(&ResticMessage::Subscribed(
+                ResticMessageSubscribed {
+                    running: running.iter().map(|(name, _)| name.clone()).collect(),
+                }
+            )).unwrap()
+        ];
+        lines.extend(running.into_iter().filter_map(|(_, status)| status));
+
+        for line in lines {
+            if writer.write_all((line + "\n").as_bytes()).await.is_err() {
+                return;
+            }
+        }
+
+        Self::forward(events, None, writer).await;
+    }
+
+    /// Writes events out until the client goes away or, if following a single
+    /// backup, until its run is finished.
+    pub(crate) async fn forward<W: AsyncWrite + Unpin>(mut events: broadcast::Receiver<Event>, only: Option<&str>, writer: &mut W) {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(count)) => {
+                    warn!("A client fell behind and missed {} events", count);
+                    continue;
+                },
+                Err(RecvError::Closed) => break,
+            };
+
+            if only.map(|name| name != event.name).unwrap_or(false) {
+                continue;
+            }
+
+            if writer.write_all((event.line + "\n").as_bytes()).await.is_err() {
+                break;
+            }
+
+            if only.is_some() && event.kind == EventKind::Finished {
+                break;
+            }
+        }
+    }
+
+    fn signal(pid: u32, signal: libc::c_int) -> Result<()> {
+        // Safe as we only ever signal children we spawned and have not reaped.
+        if unsafe { libc::kill(pid as libc::pid_t, signal) } != 0 {
+            bail!(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
@@ -1,43 +1,179 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use clap::Parser;
 use duplikat_types::*;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::net::{TcpListener, tcp::WriteHalf};
+use log::{LevelFilter, warn};
+use tokio::io::AsyncWrite;
+use config::{DAEMON_FILE, DaemonConfig};
+use history::History;
+use jobs::Jobs;
+use logger::Logs;
 use restic::{Configuration, Restic};
 
+mod access;
+mod config;
+mod history;
+mod http;
+mod jobs;
+mod logger;
 mod restic;
+mod runner;
+mod scheduler;
+mod secrets;
 
-#[allow(clippy::needless_lifetimes)]
-async fn process_request<'a>(buffer: &str, writer: &mut WriteHalf<'a>) {
-    if let Ok(result) = serde_json::from_str(buffer) {
-        match result {
-            ClientMessage::CreateBackup(create) => Restic::create_backup(&create.backup, writer).await,
-            ClientMessage::RunBackup(backup) => Restic::run_backup(&backup.name, writer).await,
-            ClientMessage::ListBackups => Configuration::list(writer).await,
+pub(crate) async fn process_request<W: AsyncWrite + Unpin>(message: ClientMessage, writer: &mut W) {
+    match message {
+        ClientMessage::CreateBackup(create) => Restic::create_backup(&create.backup, writer).await,
+        ClientMessage::UpdateBackup(update) => Restic::update_backup(&update, writer).await,
+        ClientMessage::DeleteBackup(delete) => Restic::delete_backup(&delete, writer).await,
+        ClientMessage::RunBackup(backup) => Restic::run_backup(&backup.name, writer).await,
+        ClientMessage::ListBackups => Configuration::list(writer).await,
+        ClientMessage::CancelBackup(backup) => Restic::cancel_backup(&backup.name, writer).await,
+        ClientMessage::RestoreSnapshot(restore) => Restic::restore_snapshot(&restore, writer).await,
+        ClientMessage::ListSnapshots(snapshots) => Restic::list_snapshots(&snapshots.name, writer).await,
+        ClientMessage::ListSnapshotTree(tree) => Restic::list_snapshot_tree(&tree, writer).await,
+        ClientMessage::ForgetSnapshots(forget) => Restic::forget_snapshots(&forget.name, writer).await,
+        ClientMessage::GetRunHistory(history) => History::get_run_history(&history, writer).await,
+        ClientMessage::GetLogs(logs) => Logs::get_logs(&logs, writer).await,
+        ClientMessage::RevealSecret(reveal) => Configuration::reveal_secret(&reveal, writer).await,
+        ClientMessage::SubscribeEvents => Jobs::subscribe_events(writer).await,
+    }
+}
+
+/// Runs restic backups on behalf of Duplikat clients. Options given here
+/// take precedence over the configuration file.
+#[derive(Parser, Debug)]
+#[command(name = "duplikatd", version)]
+struct Options {
+    /// The configuration file [default: duplikatd.json in the configuration directory]
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Where access rules and backups are kept
+    #[arg(long, value_name = "DIR")]
+    config_dir: Option<PathBuf>,
+    /// Where the history of runs is kept
+    #[arg(long, value_name = "DIR")]
+    state_dir: Option<PathBuf>,
+    /// Where restic keeps its cache
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+    /// The Unix socket to listen on
+    #[arg(long, value_name = "PATH")]
+    socket: Option<PathBuf>,
+    /// The address to listen for TCP connections on
+    #[arg(long, value_name = "ADDRESS")]
+    listen: Option<SocketAddr>,
+    /// Do not listen for TCP connections
+    #[arg(long, conflicts_with = "listen")]
+    no_tcp: bool,
+    /// The restic binary to use
+    #[arg(long, value_name = "PATH")]
+    restic: Option<PathBuf>,
+    /// One of off, error, warn, info, debug or trace
+    #[arg(long, value_name = "LEVEL")]
+    log_level: Option<LevelFilter>,
+    /// How many restic processes may run at the same time
+    #[arg(long, value_name = "COUNT")]
+    max_processes: Option<usize>,
+    /// Check the configuration and exit
+    #[arg(long)]
+    check_config: bool,
+}
+
+impl Options {
+    fn config_file(&self) -> PathBuf {
+        match (&self.config, &self.config_dir) {
+            (Some(config), _) => config.clone(),
+            (None, Some(dir)) => dir.join(DAEMON_FILE),
+            (None, None) => DaemonConfig::default_config_dir().join(DAEMON_FILE),
+        }
+    }
+
+    fn apply(self, config: &mut DaemonConfig) {
+        if self.config_dir.is_some() {
+            config.config_dir = self.config_dir;
+        }
+        if self.state_dir.is_some() {
+            config.state_dir = self.state_dir;
+        }
+        if self.cache_dir.is_some() {
+            config.cache_dir = self.cache_dir;
+        }
+        if self.socket.is_some() {
+            config.socket_path = self.socket;
+        }
+        if self.listen.is_some() {
+            config.tcp_address = self.listen;
+        }
+        if self.no_tcp {
+            config.tcp_address = None;
+        }
+        if let Some(restic) = self.restic {
+            config.restic = restic;
+        }
+        if let Some(log_level) = self.log_level {
+            config.log_level = log_level;
+        }
+        if let Some(max_processes) = self.max_processes {
+            config.max_processes = max_processes;
         }
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind("127.0.0.1:7667").await?;
-
-    loop {
-        let (mut socket, _) = listener.accept().await?;
-        tokio::spawn(async move {
-            let (reader, mut writer) = socket.split();
-
-            let mut reader = BufReader::new(reader);
-
-            let mut buffer = String::new();
-            while let Ok(count) = reader.read_line(&mut buffer).await {
-                if count == 0 {
-                    break;
-                }
-                process_request(buffer.trim_end(), &mut writer).await;
-                buffer.clear();
-            };
-        });
+    let options = Options::parse();
+    logger::init(options.log_level.unwrap_or(LevelFilter::Info));
+
+    let path = options.config_file();
+    let check_config = options.check_config;
+    // Checking must not change anything, so settings of older versions are
+    // only moved over on a real start.
+    let loaded = if check_config {
+        DaemonConfig::read(&path)
+    } else {
+        DaemonConfig::load(&path)
+    };
+    let mut config = match loaded {
+        Ok(config) => config,
+        Err(error) if check_config => {
+            eprintln!("{:#}", error);
+            std::process::exit(1);
+        },
+        // A broken configuration should not keep the daemon from starting.
+        Err(error) => {
+            warn!("{:#}, using the defaults", error);
+            DaemonConfig::default()
+        },
+    };
+    options.apply(&mut config);
+
+    if check_config {
+        let problems = config.check();
+        for problem in &problems {
+            eprintln!("{}", problem);
+        }
+        if !problems.is_empty() {
+            std::process::exit(1);
+        }
+        println!("{} is fine", path.display());
+        return Ok(());
+    }
+
+    log::set_max_level(config.log_level);
+    runner::Runner::set_max_processes(config.max_processes);
+    secrets::SecretStore::configure(config.secret_store);
+    let tcp_address = config.tcp_address;
+    let socket_path = config.socket_path();
+    DaemonConfig::set(config);
+    scheduler::Scheduler::start();
+
+    match (tcp_address, socket_path) {
+        (Some(address), Some(path)) => { tokio::try_join!(http::serve_tcp(address), http::serve_unix(&path))?; },
+        (Some(address), None) => http::serve_tcp(address).await?,
+        (None, Some(path)) => http::serve_unix(&path).await?,
+        (None, None) => return Err("There is nowhere to listen for clients".into()),
     }
 
-    #[allow(unreachable_code)]
     Ok(())
 }
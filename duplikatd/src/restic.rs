@@ -1,43 +1,52 @@
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{prelude::*, BufRead, BufReader};
+use std::io::prelude::*;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
-use anyhow::{Error, Result, bail};
+use std::process::{ExitStatus, Stdio};
+use anyhow::{Result, anyhow, bail};
 use duplikat_types::*;
 use futures::future::join_all;
-use log::{error,warn};
+use log::{error, info, warn};
 use serde_json::json;
-use tokio::io::AsyncWriteExt;
-use tokio::net::tcp::WriteHalf;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::process::Command;
+use chrono::{DateTime, Local};
+use crate::config::{BackupDocument, DaemonConfig};
+use crate::history::{History, MAX_ERRORS, truncate_stderr};
+use crate::jobs::{EventKind, Jobs};
+use crate::logger;
+use crate::runner::{ResticError, Runner, failure_message, server_error};
+use crate::scheduler::Scheduler;
+use crate::secrets::{SecretItem, SecretStore};
+
+// Environment variables whose values are kept out of the logs, along with
+// those ending in _KEY; key ids are fine.
+const SECRET_VARIABLES: &[&str] = &["PASSWORD", "SECRET", "TOKEN"];
+
+// Certificates used to be kept in the environment, from where they are still
+// read for backups that were not written since.
+const LEGACY_CA_CERT: &str = "RESTIC_CACERT";
+const LEGACY_CLIENT_CERT: &str = "RESTIC_TLS_CLIENT_CERT";
 
 pub(crate) struct Restic {}
 
 impl Restic {
-    #[allow(clippy::needless_lifetimes)]
-    pub(crate) async fn create_backup<'a>(backup: &Backup, writer: &mut WriteHalf<'a>) {
-        if let Err(error) = Configuration::create(&backup) {
-            send_json(
-                &json!({
-                    "error": Some(ServerError::Configuration(
-                        error.to_string())
-                    )
-                }),
-                writer
-            ).await;
+    pub(crate) async fn create_backup<W: AsyncWrite + Unpin>(backup: &Backup, writer: &mut W) {
+        if let Err(error) = Restic::check_rclone_remote(backup).await {
+            send_error(&server_error(&error, Operation::CreateBackup), writer).await;
+            return;
+        }
+
+        if let Err(error) = Configuration::create(backup) {
+            send_error(&server_error(&error, Operation::CreateBackup), writer).await;
             return;
         }
 
         if let Err(error) = Restic::create_repo(&backup.name).await {
-            send_json(
-                &json!({
-                    "error": Some(ServerError::RepoInit(
-                        error.to_string().trim().to_string())
-                    )
-                }),
-                writer
-            ).await;
-            Configuration::remove(&backup.name).await;
+            send_error(&server_error(&error, Operation::InitRepository), writer).await;
+            if let Err(error) = Configuration::remove(&backup.name).await {
+                error!("Failed to remove configuration for {}: {:#?}", backup.name, error);
+            }
             return;
         }
 
@@ -50,82 +59,581 @@ impl Restic {
     }
 
     pub(crate) async fn create_repo(name: &str) -> Result<()> {
-        let environment = Configuration::environment_for_name(name).await;
-        let child = Command::new("restic")
-            .args(&[
-                "--json",
-                "init",
-                "--repository-file", &Configuration::repo_file(name).to_string_lossy(),
-                "--password-file", &Configuration::password_file(name).to_string_lossy(),
-            ])
-            .envs(environment)
-            .stderr(Stdio::piped())
-            .spawn()
-            .expect("Failed to run restic");
-
-        let mut stderr = child.stderr.expect("Failed to open stderr");
-        let mut output = String::new();
-        stderr.read_to_string(&mut output).unwrap();
-        if !output.is_empty() {
-            bail!(output)
+        let command = Runner::command(name, &["--json", "init"]).await?;
+        Runner::output(name, command).await?.check()?;
+        Ok(())
+    }
+
+    pub(crate) async fn update_backup<W: AsyncWrite + Unpin>(update: &ClientMessageUpdateBackup, writer: &mut W) {
+        if let Err(error) = Restic::update_for(update).await {
+            send_error(&server_error(&error, Operation::UpdateBackup), writer).await;
+            return;
         }
+
+        send_json(
+            &json!({
+                "message": "OK"
+            }),
+            writer
+        ).await;
+    }
+
+    async fn update_for(update: &ClientMessageUpdateBackup) -> Result<()> {
+        let name = &update.name;
+
+        Configuration::ensure_exists(name)?;
+        let previous = Configuration::backup_with_name(name).await?;
+
+        // Clients are not told the secrets, so they send them back empty
+        // unless they are changing them. Those that point the backup somewhere
+        // else have to send them again, or we would hand them to a server
+        // they might not have been trusted with.
+        let mut backup = update.backup.clone();
+        backup.keep_secrets(&previous);
+        if backup.lacks_secrets_of(&previous) {
+            bail!(ServerError::BadRequest {
+                detail: "The password and secrets have to be given again when the repository or credentials change".to_string(),
+            });
+        }
+        let backup = &backup;
+
+        // A running restic is using the files we are about to replace.
+        if Jobs::is_running(name) {
+            bail!(ServerError::Busy { name: name.to_string() });
+        }
+
+        Configuration::validate_repository(backup)?;
+        Restic::check_rclone_remote(backup).await?;
+        if backup.name != *name {
+            Configuration::rename(name, &backup.name).await?;
+        }
+
+        // The repository was initialized when the backup was created, so only
+        // make sure we can still get to it if anything that affects that changed.
+        let repository_changed = previous.repository != backup.repository
+            || previous.password != backup.password
+            || previous.credentials != backup.credentials
+            || previous.ca_cert != backup.ca_cert
+            || previous.client_cert != backup.client_cert
+            || previous.rclone != backup.rclone;
+
+        let mut result = Configuration::write(backup);
+        if result.is_ok() && repository_changed {
+            result = Restic::check_repo(&backup.name).await;
+        }
+
+        if let Err(error) = result {
+            // Go back to what we had, so the backup keeps working.
+            let mut previous_at_new_name = previous;
+            previous_at_new_name.name = backup.name.clone();
+            if let Err(error) = Configuration::write(&previous_at_new_name) {
+                error!("Failed to restore configuration for {}: {:#?}", name, error);
+            }
+            if backup.name != *name {
+                if let Err(error) = Configuration::rename(&backup.name, name).await {
+                    error!("Failed to restore name of {}: {:#?}", name, error);
+                }
+            }
+            return Err(error);
+        }
+
         Ok(())
     }
 
-    #[allow(clippy::needless_lifetimes)]
-    pub async fn run_backup<'a>(name: &str, writer: &mut WriteHalf<'a>) {
-        let environment = Configuration::environment_for_name(name).await;
-        let child = Command::new("restic")
-            .args(&[
-                "--json",
-                "backup",
-                "--files-from", &Configuration::include_file(name).to_string_lossy(),
-                "--exclude-file", &Configuration::exclude_file(name).to_string_lossy(),
-                "--repository-file", &Configuration::repo_file(name).to_string_lossy(),
-                "--password-file", &Configuration::password_file(name).to_string_lossy(),
-            ])
-            .envs(environment)
-            .stdout(Stdio::piped())
-            .spawn()
-            .expect("Failed to run restic");
-
-        let output = child.stdout.expect("Failed to open stdout");
-        let output = BufReader::new(output);
-        for line in output.lines() {
-            let mut line = line.unwrap();
-            line.push('\n');
-            if let Err(error) = writer.write_all(line.as_bytes()).await {
-                error!("{:#?}", error);
+    pub(crate) async fn delete_backup<W: AsyncWrite + Unpin>(delete: &ClientMessageDeleteBackup, writer: &mut W) {
+        if let Err(error) = Restic::delete_for(delete).await {
+            send_error(&server_error(&error, Operation::DeleteBackup), writer).await;
+            return;
+        }
+
+        send_json(
+            &json!({
+                "message": "OK"
+            }),
+            writer
+        ).await;
+    }
+
+    async fn delete_for(delete: &ClientMessageDeleteBackup) -> Result<()> {
+        let name = &delete.name;
+        Configuration::ensure_exists(name)?;
+        let backup = Configuration::backup_with_name(name).await?;
+
+        if Jobs::is_running(name) {
+            bail!(ServerError::Busy { name: name.to_string() });
+        }
+
+        // The repository goes first, so that if removing it fails the backup
+        // is still around to retry.
+        if delete.purge_repository {
+            if backup.repository.kind != RepositoryKind::Local {
+                bail!(ServerError::BadRequest {
+                    detail: format!("Only local repositories can be purged, {} is not one", name),
+                });
+            }
+
+            // Make very sure this is a restic repository before wiping it out,
+            // a typo in the path should not cost anyone their home directory.
+            let path = Path::new(&backup.repository.path);
+            if !path.join("config").is_file() || !path.join("data").is_dir() {
+                bail!("{} does not look like a restic repository, not removing it", path.display());
+            }
+
+            tokio::fs::remove_dir_all(path).await?;
+        }
+
+        Configuration::remove(name).await
+    }
+
+    async fn check_repo(name: &str) -> Result<()> {
+        let command = Runner::command(name, &["cat", "config"]).await?;
+        Runner::output(name, command).await?.check()?;
+        Ok(())
+    }
+
+    /// Restic only finds out a remote is missing when it is first used, and
+    /// says so in a way that is hard to make sense of, so ask rclone upfront.
+    async fn check_rclone_remote(backup: &Backup) -> Result<()> {
+        if backup.repository.kind != RepositoryKind::Rclone {
+            return Ok(());
+        }
+
+        let options = backup.rclone.clone().unwrap_or_default();
+        let program = DaemonConfig::get().rclone_program.clone().unwrap_or_else(|| "rclone".into());
+        let mut command = Command::new(program);
+        command.arg("listremotes").stdin(Stdio::null());
+        if let Some(config) = &options.config {
+            command.env("RCLONE_CONFIG", config);
+        }
+
+        let output = command.output().await
+            .map_err(|error| ServerError::BadRequest { detail: format!("Could not run rclone: {}", error) })?;
+        if !output.status.success() {
+            bail!(ServerError::BadRequest {
+                detail: format!(
+                    "rclone could not list its remotes: {}",
+                    failure_message(output.status, &String::from_utf8_lossy(&output.stderr))
+                ),
+            });
+        }
+
+        let remote = format!("{}:", backup.repository.identifier);
+        if !String::from_utf8_lossy(&output.stdout).lines().any(|line| line.trim() == remote) {
+            bail!(ServerError::BadRequest {
+                detail: format!("There is no rclone remote called {}", backup.repository.identifier),
+            });
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn run_backup<W: AsyncWrite + Unpin>(name: &str, writer: &mut W) {
+        // Subscribe before starting, so that we do not miss anything.
+        let (_, events) = Jobs::subscribe();
+
+        if let Err(error) = Restic::start_backup(name, RunTrigger::Manual).await {
+            send_error(&server_error(&error, Operation::RunBackup), writer).await;
+            return;
+        }
+
+        // The backup carries on if the client goes away, it can follow it
+        // again by subscribing to events.
+        Jobs::forward(events, Some(name), writer).await;
+    }
+
+    /// Queues a backup run, which starts as soon as few enough other restic
+    /// processes are running and is followed through events from then on.
+    pub(crate) async fn start_backup(name: &str, trigger: RunTrigger) -> Result<()> {
+        let started = Local::now();
+        let document = Configuration::document_for_name(name)?;
+        let mut args = vec!["--json".to_string(), "backup".to_string()];
+        args.extend(document.exclude.iter().map(|pattern| format!("--exclude={}", pattern)));
+        args.push("--".to_string());
+        args.extend(document.include.iter().map(|path| path.to_string_lossy().to_string()));
+
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let command = Runner::command(name, &args).await?;
+
+        Jobs::register(name)?;
+
+        let name = name.to_string();
+        tokio::spawn(async move {
+            Restic::follow_backup(&name, trigger, started, command).await;
+        });
+
+        Ok(())
+    }
+
+    async fn follow_backup(name: &str, trigger: RunTrigger, started: DateTime<Local>, command: Command) {
+        let mut summary = None;
+        let mut errors = vec![];
+        let result = Self::stream_backup(name, command, &mut summary, &mut errors).await;
+        let cancelled = Jobs::unregister(name);
+
+        let stderr = match &result {
+            Ok((_, stderr)) => stderr.clone(),
+            Err(_) => String::new(),
+        };
+        let (outcome, error) = match result {
+            _ if cancelled => (RunOutcome::Cancelled, None),
+            Ok((status, stderr)) => match ResticError::from_exit(status, &stderr) {
+                None => (RunOutcome::Succeeded, None),
+                // The files that could not be read were already sent as error
+                // messages.
+                Some(error @ ResticError::Incomplete(_)) => (
+                    RunOutcome::Partial,
+                    Some(error.to_server_error(Operation::RunBackup)),
+                ),
+                Some(error) => (
+                    RunOutcome::Failed,
+                    Some(error.to_server_error(Operation::RunBackup)),
+                ),
+            },
+            Err(error) => (RunOutcome::Failed, Some(server_error(&error, Operation::RunBackup))),
+        };
+
+        let target = logger::target(name);
+        if let Some(error) = &error {
+            let message = match error.detail() {
+                Some(detail) => format!("{} {}", error, detail),
+                None => error.to_string(),
+            };
+            match outcome {
+                RunOutcome::Partial => warn!(target: &target, "{}", message),
+                _ => error!(target: &target, "{}", message),
+            }
+        } else if cancelled {
+            info!(target: &target, "Backup cancelled");
+        } else {
+            info!(target: &target, "Backup finished");
+        }
+
+        let record = RunRecord {
+            started: started.to_rfc3339(),
+            finished: Local::now().to_rfc3339(),
+            trigger,
+            outcome,
+            summary,
+            error,
+            errors,
+            stderr: truncate_stderr(&logger::redact(&stderr)),
+        };
+        if let Err(error) = History::record(name, &record) {
+            error!(target: &target, "Failed to record the run: {:#}", error);
+        }
+
+        if cancelled {
+            let message = ResticMessage::Cancelled(
+                ResticMessageCancelled {
+                    name: name.to_string(),
+                }
+            );
+            Jobs::publish(name, EventKind::Message, serde_json::to_string(&message).unwrap());
+        }
+
+        let message = ResticMessage::RunFinished(
+            ResticMessageRunFinished {
+                name: name.to_string(),
+                run: record,
+            }
+        );
+        Jobs::publish(name, EventKind::Finished, serde_json::to_string(&message).unwrap());
+    }
+
+    /// Publishes what restic prints while backing up, keeping the summary and
+    /// the first few files it could not back up.
+    async fn stream_backup(name: &str, command: Command, summary: &mut Option<ResticMessageSummary>, errors: &mut Vec<ResticMessageError>) -> Result<(ExitStatus, String)> {
+        let slot = Runner::slot().await?;
+        if Jobs::is_cancelled(name) {
+            bail!("Cancelled before starting");
+        }
+
+        // Dropping the process kills it, which takes care of it being cancelled
+        // right as it was started.
+        let mut process = Runner::spawn_in(name, slot, command)?;
+        let pid = process.id().expect("restic has not been waited for yet");
+        if !Jobs::started(name, pid) {
+            bail!("Cancelled before starting");
+        }
+
+        let target = logger::target(name);
+        info!(target: &target, "Backup started");
+
+        while let Some(line) = process.next_line().await? {
+            let mut value: serde_json::Value = match serde_json::from_str(&line) {
+                Ok(value) => value,
+                Err(_) => { warn!(target: &target, "Unexpected output from restic: {}", line); continue },
+            };
+
+            // Clients following every backup need to know which one this is.
+            if let Some(object) = value.as_object_mut() {
+                object.insert("name".to_string(), json!(name));
+            }
+
+            let kind = match value["message_type"].as_str() {
+                Some("status") => EventKind::Status,
+                Some("error") => {
+                    // Passed on in our own shape, as restic's changes between
+                    // versions and has an error key that clients could take
+                    // for a failed request.
+                    let error = error_message(name, &value);
+                    if errors.len() < MAX_ERRORS {
+                        errors.push(error.clone());
+                    }
+                    let message = ResticMessage::Error(error);
+                    Jobs::publish(name, EventKind::Message, serde_json::to_string(&message).unwrap());
+                    continue;
+                },
+                _ => EventKind::Message,
+            };
+
+            if let Ok(ResticMessage::Summary(message)) = serde_json::from_value(value.clone()) {
+                summary.replace(message);
+            }
+
+            Jobs::publish(name, kind, value.to_string());
+        }
+
+        process.wait().await
+    }
+
+    pub(crate) async fn cancel_backup<W: AsyncWrite + Unpin>(name: &str, writer: &mut W) {
+        if let Err(error) = Configuration::ensure_exists(name).and_then(|_| Jobs::cancel(name)) {
+            send_error(&server_error(&error, Operation::CancelBackup), writer).await;
+            return;
+        }
+
+        send_json(
+            &json!({
+                "message": "OK"
+            }),
+            writer
+        ).await;
+    }
+
+    pub(crate) async fn restore_snapshot<W: AsyncWrite + Unpin>(restore: &ClientMessageRestoreSnapshot, writer: &mut W) {
+        if let Err(error) = Restic::validate_snapshot_id(&restore.snapshot) {
+            send_error(&server_error(&error, Operation::RestoreSnapshot), writer).await;
+            return;
+        }
+
+        let target = restore.target.to_string_lossy();
+        let mut args = vec![
+            "--json",
+            "restore",
+            "--target", &target,
+        ];
+        for pattern in &restore.include {
+            args.push("--include");
+            args.push(pattern);
+        }
+        for pattern in &restore.exclude {
+            args.push("--exclude");
+            args.push(pattern);
+        }
+        args.push("--");
+        args.push(&restore.snapshot);
+
+        let process = match Runner::command(&restore.name, &args).await {
+            Ok(command) => Runner::spawn(&restore.name, command).await,
+            Err(error) => Err(error),
+        };
+        let mut process = match process {
+            Ok(process) => process,
+            Err(error) => {
+                send_error(&server_error(&error, Operation::RestoreSnapshot), writer).await;
+                return;
+            },
+        };
+
+        // restic uses the same message types for restore as it does for backup,
+        // so give them their own names before passing them on.
+        let target = logger::target(&restore.name);
+        while let Ok(Some(line)) = process.next_line().await {
+            let value: serde_json::Value = match serde_json::from_str(&line) {
+                Ok(value) => value,
+                Err(_) => { warn!(target: &target, "Unexpected output from restic: {}", line); continue },
+            };
+
+            let message = match value["message_type"].as_str() {
+                Some("status") => serde_json::from_value(value)
+                    .map(ResticMessage::RestoreStatus),
+                Some("summary") => serde_json::from_value(value)
+                    .map(ResticMessage::RestoreSummary),
+                _ => continue,
+            };
+
+            match message {
+                Ok(message) => send_message(&message, writer).await,
+                Err(error) => error!("{:#?}", error),
+            }
+        }
+
+        let error = match process.wait().await {
+            Ok((status, stderr)) => match ResticError::from_exit(status, &stderr) {
+                Some(error) => error.to_server_error(Operation::RestoreSnapshot),
+                None => return,
+            },
+            Err(error) => server_error(&error, Operation::RestoreSnapshot),
+        };
+
+        send_error(&error, writer).await;
+    }
+
+    pub(crate) async fn list_snapshots<W: AsyncWrite + Unpin>(name: &str, writer: &mut W) {
+        match Restic::snapshots_for(name).await {
+            Ok(list) => {
+                let message = ResticMessage::SnapshotsList(
+                    ResticMessageSnapshotsList {
+                        name: name.to_string(),
+                        list,
+                    }
+                );
+                send_message(&message, writer).await;
+            },
+            Err(error) => {
+                send_error(&server_error(&error, Operation::ListSnapshots), writer).await;
+            },
+        }
+    }
+
+    pub(crate) async fn snapshots_for(name: &str) -> Result<Vec<Snapshot>> {
+        let command = Runner::command(name, &["--json", "snapshots"]).await?;
+
+        // Snapshots are printed as a single JSON array.
+        let output = Runner::output(name, command).await?.check()?;
+        Ok(serde_json::from_str(&output.stdout)?)
+    }
+
+    pub(crate) async fn list_snapshot_tree<W: AsyncWrite + Unpin>(request: &ClientMessageListSnapshotTree, writer: &mut W) {
+        match Restic::snapshot_tree_for(request).await {
+            Ok(entries) => {
+                let message = ResticMessage::SnapshotTree(
+                    ResticMessageSnapshotTree {
+                        name: request.name.clone(),
+                        snapshot: request.snapshot.clone(),
+                        path: request.path.clone(),
+                        entries,
+                    }
+                );
+                send_message(&message, writer).await;
+            },
+            Err(error) => {
+                send_error(&server_error(&error, Operation::ListSnapshotTree), writer).await;
+            },
+        }
+    }
+
+    /// Snapshots are passed to restic as arguments, so only what restic takes
+    /// for a snapshot id is accepted.
+    fn validate_snapshot_id(snapshot: &str) -> Result<()> {
+        let is_id = !snapshot.is_empty()
+            && snapshot.len() <= 64
+            && snapshot.chars().all(|c| c.is_ascii_hexdigit());
+        if is_id || snapshot == "latest" {
+            return Ok(());
+        }
+
+        bail!(ServerError::BadRequest { detail: format!("{:?} is not a snapshot id", snapshot) })
+    }
+
+    async fn snapshot_tree_for(request: &ClientMessageListSnapshotTree) -> Result<Vec<SnapshotNode>> {
+        Restic::validate_snapshot_id(&request.snapshot)?;
+
+        // Without --recursive restic only lists the directory itself and its
+        // immediate children, which is what we want for browsing lazily.
+        let command = Runner::command(&request.name, &[
+            "--json",
+            "ls",
+            "--",
+            &request.snapshot,
+            &request.path,
+        ]).await?;
+
+        let output = Runner::output(&request.name, command).await?.check()?;
+
+        // The first line describes the snapshot, all others are nodes. Newer
+        // restic versions call struct_type message_type.
+        let mut entries = vec![];
+        for line in output.stdout.lines() {
+            let value: serde_json::Value = serde_json::from_str(line)?;
+            let struct_type = value.get("struct_type")
+                .or_else(|| value.get("message_type"))
+                .and_then(|t| t.as_str());
+            if struct_type != Some("node") {
+                continue;
+            }
+
+            let node: SnapshotNode = serde_json::from_value(value)?;
+            if node.path != request.path {
+                entries.push(node);
             }
         }
+
+        Ok(entries)
+    }
+
+    pub(crate) async fn forget_snapshots<W: AsyncWrite + Unpin>(name: &str, writer: &mut W) {
+        match Restic::forget_for(name).await {
+            Ok((keep, remove)) => {
+                let message = ResticMessage::ForgetSummary(
+                    ResticMessageForgetSummary {
+                        name: name.to_string(),
+                        keep,
+                        remove,
+                    }
+                );
+                send_message(&message, writer).await;
+            },
+            Err(error) => {
+                send_error(&server_error(&error, Operation::ForgetSnapshots), writer).await;
+            },
+        }
+    }
+
+    async fn forget_for(name: &str) -> Result<(Vec<Snapshot>, Vec<Snapshot>)> {
+        #[derive(serde::Deserialize)]
+        struct ForgetGroup {
+            keep: Option<Vec<Snapshot>>,
+            remove: Option<Vec<Snapshot>>,
+        }
+
+        let policy = Configuration::retention_for_name(name)?;
+        if policy.is_empty() {
+            bail!("No retention policy configured for {}", name);
+        }
+
+        let mut command = Runner::command(name, &["--json", "forget", "--prune"]).await?;
+        command.args(policy.to_args());
+
+        let output = Runner::output(name, command).await?.check()?;
+
+        // The groups of kept and removed snapshots come as a single JSON array,
+        // but prune may print plain text progress after it.
+        let groups = output.stdout.lines()
+            .find_map(|line| serde_json::from_str::<Vec<ForgetGroup>>(line).ok())
+            .unwrap_or_default();
+
+        let mut keep = vec![];
+        let mut remove = vec![];
+        for group in groups {
+            keep.extend(group.keep.unwrap_or_default());
+            remove.extend(group.remove.unwrap_or_default());
+        }
+
+        Ok((keep, remove))
     }
 
     pub(crate) async fn stats_for(name: String) -> Result<(String, String)> {
-        let environment = Configuration::environment_for_name(&name).await;
-        let child = Command::new("restic")
-            .args(&[
-                "--json",
-                "stats",
-                "--repository-file", &Configuration::repo_file(&name).to_string_lossy(),
-                "--password-file", &Configuration::password_file(&name).to_string_lossy(),
-            ])
-            .envs(environment)
-            .stdout(Stdio::piped())
-            .spawn()
-            .expect("Failed to run restic");
-
-        let output = child.stdout.expect("Failed to open stdout");
-        let output = BufReader::new(output);
+        let command = Runner::command(&name, &["--json", "stats"]).await?;
+        let output = Runner::output(&name, command).await?.check()?;
 
         // Stats only return a single line that looks like this:
         // {"total_size":2349097,"total_file_count":8}
-        output.lines()
-            .collect::<std::io::Result<Vec<String>>>()
-            .map_err(Error::new)
-            .map(|mut lines| {
-                assert_eq!(lines.len(), 1);
-                (name, lines.pop().unwrap())
-            })
+        match output.stdout.lines().next() {
+            Some(line) => Ok((name, line.to_string())),
+            None => bail!("No stats from restic for {}", name),
+        }
     }
 }
 
@@ -133,40 +641,95 @@ pub struct Configuration {}
 
 impl Configuration {
     pub async fn backup_with_name(name: &str) -> Result<Backup> {
+        let document = Self::document_for_name(name)?;
+        let password = Self::password_for_name(name)?;
+
+        let mut environment = Self::environment_for_name(name).await;
+        let credentials = Credentials::from_environment(&document.repository.kind, &mut environment);
+        let ca_cert = document.ca_cert.or_else(|| environment.remove(LEGACY_CA_CERT).map(PathBuf::from));
+        let client_cert = document.client_cert.or_else(|| environment.remove(LEGACY_CLIENT_CERT).map(PathBuf::from));
+
+        Ok(Backup {
+            name: name.to_string(),
+            repository: document.repository,
+            password,
+            credentials,
+            ca_cert,
+            client_cert,
+            rclone: document.rclone,
+            include: document.include,
+            exclude: document.exclude,
+            retention: document.retention,
+            schedule: document.schedule,
+        })
+    }
+
+    /// The configuration of a backup, apart from its secrets.
+    pub(crate) fn document_for_name(name: &str) -> Result<BackupDocument> {
+        Self::ensure_exists(name)?;
+
         let mut base_path = Self::base_config_path();
         base_path.push(name);
 
-        // Ensure it's a directory?
-        let _ = tokio::fs::metadata(base_path.as_path()).await?;
+        BackupDocument::load(&base_path)
+    }
 
-        let repository = Repository::from(
-            Self::read_file(
-                Self::repo_file(name).as_path()
-            ).unwrap().as_str()
-        );
+    /// Sends the password and credentials of a backup, for the rare times
+    /// someone needs them back. Whether they may is up to the caller.
+    pub(crate) async fn reveal_secret<W: AsyncWrite + Unpin>(request: &ClientMessageRevealSecret, writer: &mut W) {
+        let backup = match Self::ensure_exists(&request.name) {
+            Ok(()) => Self::backup_with_name(&request.name).await,
+            Err(error) => Err(error),
+        };
 
-        let password = Self::read_file(
-            Self::password_file(name).as_path()
-        ).unwrap();
+        match backup {
+            Ok(backup) => {
+                let message = ResticMessage::Secret(
+                    ResticMessageSecret {
+                        name: backup.name,
+                        password: backup.password,
+                        credentials: backup.credentials,
+                    }
+                );
+                send_message(&message, writer).await;
+            },
+            Err(error) => {
+                send_error(&server_error(&error, Operation::RevealSecret), writer).await;
+            },
+        }
+    }
 
+    pub(crate) async fn names() -> Result<Vec<String>> {
+        let mut entries = tokio::fs::read_dir(Self::base_config_path()).await?;
 
-        let include = Self::read_file_to_vec::<PathBuf>(
-            Self::include_file(name).as_path()
-        ).unwrap();
+        let mut names = vec![];
+        while let Some(entry) = entries.next_entry().await? {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+        Ok(names)
+    }
 
-        let exclude = Self::read_file_to_vec::<String>(
-            Self::exclude_file(name).as_path()
-        ).unwrap();
+    pub(crate) fn schedule_for_name(name: &str) -> Result<Option<Schedule>> {
+        Ok(Self::document_for_name(name)?.schedule)
+    }
 
-        Ok(Backup {
-            name: name.to_string(),
-            repository,
-            password,
-            key_id: None,
-            key_secret: None,
-            include,
-            exclude,
-        })
+    pub(crate) fn last_scheduled_run(name: &str) -> Option<DateTime<Local>> {
+        let last_run = Self::read_file(Self::last_scheduled_run_file(name).as_path()).ok()?;
+        DateTime::parse_from_rfc3339(last_run.trim())
+            .map(|last_run| last_run.with_timezone(&Local))
+            .ok()
+    }
+
+    pub(crate) fn set_last_scheduled_run(name: &str, time: DateTime<Local>) -> Result<()> {
+        let mut base_path = Self::base_state_path();
+        base_path.push(name);
+        std::fs::create_dir_all(&base_path)?;
+
+        Self::write_str_to_file(&base_path, "last_scheduled_run", &time.to_rfc3339())
+    }
+
+    pub(crate) fn retention_for_name(name: &str) -> Result<RetentionPolicy> {
+        Ok(Self::document_for_name(name)?.retention.unwrap_or_default())
     }
 
     fn read_file(path: &Path) -> Result<String> {
@@ -176,93 +739,224 @@ impl Configuration {
         Ok(contents)
     }
 
-    fn read_file_to_vec<T>(path: &Path) -> Result<Vec<T>>
-    where T: From<String>
-    {
-        let mut repo_file = BufReader::new(
-            File::open(path)?
-        );
-        let mut contents = String::new();
-        let mut vector = vec![];
-        while repo_file.read_line(&mut contents)? != 0 {
-            vector.push(T::from(contents.trim().to_string()));
-            contents.clear();
+    pub fn create(backup: &Backup) -> Result<()> {
+        Self::validate_name(&backup.name)?;
+        Self::validate_repository(backup)?;
+        if backup.password.is_empty() {
+            bail!(ServerError::BadRequest { detail: "The repository needs a password".to_string() });
+        }
+        if Self::exists(&backup.name) {
+            bail!(ServerError::AlreadyExists { name: backup.name.clone() });
         }
-        Ok(vector)
-    }
 
-    pub fn create(backup: &Backup) -> Result<()> {
         let mut base_path = Self::base_config_path();
         base_path.push(&backup.name);
 
         std::fs::create_dir_all(&*base_path.to_string_lossy())?;
 
-        let repository_string = backup.repository.to_string();
-        Self::write_str_to_file(&base_path, "repo", &repository_string)?;
-        Self::write_str_to_file(&base_path, "password", &backup.password)?;
-        Self::write_include_file(&base_path, &backup.include)?;
-        Self::write_exclude_file(&base_path, &backup.exclude)?;
-
-        match backup.repository.kind {
-            RepositoryKind::B2 => {
-                Self::write_str_to_file(
-                    &base_path, "environment",
-                    &format!(
-                        "B2_ACCOUNT_ID={}\nB2_ACCOUNT_KEY={}\n",
-                        backup.key_id.as_ref().unwrap(),
-                        backup.key_secret.as_ref().unwrap(),
-                    )
-                )?;
-            },
-            _ => ()
+        Self::write(backup)
+    }
+
+    /// Writes the definition of an existing backup, replacing whatever it had
+    /// before, including the optional bits it no longer uses.
+    pub(crate) fn write(backup: &Backup) -> Result<()> {
+        let mut base_path = Self::base_config_path();
+        base_path.push(&backup.name);
+
+        if let Some(schedule) = &backup.schedule {
+            Scheduler::validate(schedule)
+                .map_err(|error| ServerError::BadRequest { detail: error.to_string() })?;
+        }
+        BackupDocument::from_backup(backup).save(&base_path)?;
+
+        let store = SecretStore::get();
+        store.write(&backup.name, SecretItem::Password, backup.password.expose())?;
+
+        let environment = backup.credentials.to_environment();
+        if environment.is_empty() {
+            store.remove(&backup.name, SecretItem::Environment)?;
+        } else {
+            let environment: String = environment.iter()
+                .map(|(variable, value)| format!("{}={}\n", variable, value))
+                .collect();
+            store.write(&backup.name, SecretItem::Environment, &environment)?;
+        }
+
+        Ok(())
+    }
+
+    /// Names come from clients, which could otherwise point us outside of
+    /// the backups directory.
+    pub(crate) fn exists(name: &str) -> bool {
+        if Self::validate_name(name).is_err() {
+            return false;
+        }
+
+        let mut path = Self::base_config_path();
+        path.push(name);
+        path.is_dir()
+    }
+
+    pub(crate) fn ensure_exists(name: &str) -> Result<()> {
+        Self::validate_name(name)?;
+        if !Self::exists(name) {
+            bail!(ServerError::NotFound { name: name.to_string() });
+        }
+        Ok(())
+    }
+
+    /// Names are used for the directory holding the configuration.
+    pub(crate) fn validate_name(name: &str) -> Result<()> {
+        let reason = if name.trim().is_empty() {
+            "it is empty"
+        } else if name.contains('/') {
+            "it contains a slash"
+        } else if name.starts_with('.') {
+            "it starts with a dot"
+        } else if name.chars().any(|c| c.is_control()) {
+            "it contains control characters"
+        } else if name.len() > 255 {
+            "it is too long"
+        } else {
+            return Ok(());
+        };
+
+        bail!(ServerError::InvalidName { name: name.to_string(), reason: reason.to_string() })
+    }
+
+    /// Repositories and their credentials are stored as restic reads them,
+    /// so they have to survive that.
+    fn validate_repository(backup: &Backup) -> Result<()> {
+        backup.repository.validate()
+            .map_err(|error| ServerError::BadRequest { detail: error.to_string() })?;
+
+        if let Some(kind) = backup.credentials.kind() {
+            if kind != backup.repository.kind {
+                bail!(ServerError::BadRequest {
+                    detail: format!("{} credentials cannot be used with a {} repository", kind, backup.repository.kind),
+                });
+            }
+        }
+
+        let environment = backup.credentials.to_environment();
+        if let Some((variable, _)) = environment.iter().find(|(_, value)| value.contains('\n')) {
+            bail!(ServerError::BadRequest { detail: format!("{} cannot span several lines", variable) });
+        }
+        for certificate in backup.ca_cert.iter().chain(&backup.client_cert) {
+            if certificate.to_string_lossy().chars().any(|c| c.is_control()) {
+                bail!(ServerError::BadRequest {
+                    detail: format!("{:?} cannot be used as a certificate", certificate),
+                });
+            }
         }
 
         Ok(())
     }
 
-    pub(crate) async fn remove(name: &str) {
+    pub(crate) async fn rename(from: &str, to: &str) -> Result<()> {
+        let mut from_path = Self::base_config_path();
+        from_path.push(from);
+
+        let mut to_path = Self::base_config_path();
+        to_path.push(to);
+
+        Self::validate_name(to)?;
+        if Self::exists(to) {
+            bail!(ServerError::AlreadyExists { name: to.to_string() });
+        }
+
+        tokio::fs::rename(&from_path, &to_path).await?;
+        if Self::base_state_path() != Self::base_config_path() {
+            let mut from_path = Self::base_state_path();
+            from_path.push(from);
+            if from_path.exists() {
+                let mut to_path = Self::base_state_path();
+                to_path.push(to);
+                tokio::fs::rename(&from_path, &to_path).await?;
+            }
+        }
+        SecretStore::get().rename(from, to)
+    }
+
+    pub(crate) async fn remove(name: &str) -> Result<()> {
         let mut path = Self::base_config_path();
         path.push(name);
 
-        tokio::fs::remove_dir_all(path.as_path()).await.unwrap();
+        let store = SecretStore::get();
+        store.remove(name, SecretItem::Password)?;
+        store.remove(name, SecretItem::Environment)?;
+
+        tokio::fs::remove_dir_all(path.as_path()).await?;
+        if Self::base_state_path() != Self::base_config_path() {
+            let mut path = Self::base_state_path();
+            path.push(name);
+            if path.exists() {
+                tokio::fs::remove_dir_all(path.as_path()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn password_for_name(name: &str) -> Result<Secret> {
+        let password = SecretStore::get().read(name, SecretItem::Password)?
+            .ok_or_else(|| anyhow!("{} has no password", name))?;
+        logger::add_secret(&password);
+        Ok(password.into())
     }
 
     pub(crate) async fn environment_for_name(name: &str) -> HashMap<String, String> {
         let mut environment = HashMap::<String, String>::new();
 
-        let variables = match Self::read_file_to_vec::<String>(
-            Self::environment_file(name).as_path()
-        ) {
-            Ok(v) => v,
-            Err(_) => return environment, // FIXME: check if error is expected (not found) or not
+        let variables = match SecretStore::get().read(name, SecretItem::Environment) {
+            Ok(variables) => variables.unwrap_or_default(),
+            Err(error) => {
+                warn!("Could not read the environment of {}: {:#}", name, error);
+                return environment;
+            },
         };
 
-        for variable in variables {
-            let (key, value) = variable.split_once("=").unwrap();
-            environment.insert(key.to_string(), value.to_string());
+        for variable in variables.lines() {
+            if let Some((key, value)) = variable.split_once('=') {
+                // Whoever can write the file should not get to set anything
+                // else in restic's environment, LD_PRELOAD say.
+                if !Credentials::VARIABLES.contains(&key) && key != LEGACY_CA_CERT && key != LEGACY_CLIENT_CERT {
+                    warn!("Ignoring {} in the environment of {}", key, name);
+                    continue;
+                }
+                if SECRET_VARIABLES.iter().any(|secret| key.contains(secret)) || key.ends_with("_KEY") {
+                    logger::add_secret(value);
+                }
+                environment.insert(key.to_string(), value.to_string());
+            }
         }
 
         environment
     }
 
-    #[allow(clippy::needless_lifetimes)]
-    pub async fn list<'a>(writer: &mut WriteHalf<'a>) {
-        let base_path = Self::base_config_path();
-        let mut entries = match tokio::fs::read_dir(base_path).await {
-            Ok(entries) => entries,
+    pub async fn list<W: AsyncWrite + Unpin>(writer: &mut W) {
+        let names = match Self::names().await {
+            Ok(names) => names,
             Err(error) => { warn!("{:#?}", error); return },
         };
 
         let mut backups = vec![];
-        while let Some(entry) = entries.next_entry().await.unwrap() {
-            backups.push(
-                Self::backup_with_name(
-                    entry.file_name()
-                        .to_string_lossy()
-                        .to_string()
-                        .as_str()
-                ).await.unwrap()
-            );
+        let mut next_runs = HashMap::new();
+        let mut last_runs = HashMap::new();
+        for name in names {
+            if let Some(next_run) = Scheduler::next_run_for(&name) {
+                next_runs.insert(name.clone(), next_run.to_rfc3339());
+            }
+            match History::runs_for(&name, Some(1)) {
+                Ok(mut runs) => if let Some(run) = runs.pop() {
+                    last_runs.insert(name.clone(), run);
+                },
+                Err(error) => warn!("{:#?}", error),
+            }
+            // One broken backup should not hide the others.
+            match Self::backup_with_name(&name).await {
+                Ok(backup) => backups.push(backup.summary()),
+                Err(error) => warn!("Skipping {}: {:#}", name, error),
+            }
         }
 
         let stats_futures: Vec<_> = backups.iter()
@@ -274,6 +968,8 @@ impl Configuration {
         let message = ResticMessage::BackupsList(
             ResticMessageBackupsList {
                 list: backups,
+                next_runs,
+                last_runs,
             }
         );
 
@@ -282,8 +978,10 @@ impl Configuration {
         let lines = join_all(stats_futures).await;
 
         for line in lines {
-            let (name, stats) = line.unwrap();
-            dbg!(&Configuration::backup_with_name(&name).await);
+            let (name, stats) = match line {
+                Ok(line) => line,
+                Err(error) => { warn!("{:#}", error); continue },
+            };
             let line = add_message_type(
                 &stats,
                 "backupstats"
@@ -294,7 +992,9 @@ impl Configuration {
                 name,
             );
             line.push('\n');
-            writer.write_all(line.as_bytes()).await.unwrap();
+            if writer.write_all(line.as_bytes()).await.is_err() {
+                return;
+            }
         }
     }
 
@@ -308,91 +1008,157 @@ impl Configuration {
         Ok(())
     }
 
-    fn write_include_file(base_path: &Path, include: &[PathBuf]) -> Result<()> {
-        let mut file_path = base_path.to_path_buf();
-        file_path.push("include");
-
-        let mut file = File::create(&*file_path.to_string_lossy())?;
-        for path in include {
-            file.write_all(path.to_string_lossy().as_bytes())?;
-            file.write_all("\n".as_bytes())?;
-        }
-
-        Ok(())
+    pub(crate) fn daemon_config_path() -> std::path::PathBuf {
+        DaemonConfig::get().config_dir()
     }
 
-    fn write_exclude_file(base_path: &Path, exclude: &[String]) -> Result<()> {
-        let mut file_path = base_path.to_path_buf();
-        file_path.push("exclude");
-
-        let mut file = File::create(&*file_path.to_string_lossy())?;
-        for pattern in exclude {
-            file.write_all(pattern.as_bytes())?;
-            file.write_all("\n".as_bytes())?;
-        }
-
-        Ok(())
+    fn base_config_path() -> std::path::PathBuf {
+        let mut base_path = Self::daemon_config_path();
+        base_path.push("backups");
+        base_path
     }
 
-    fn base_config_path() -> std::path::PathBuf {
-        let mut base_path = match users::get_effective_uid() {
-            0 => {
-                let mut base_path = std::path::PathBuf::from("/");
-                base_path.push("etc");
-                base_path
-            },
-            _ => {
-                dirs::config_dir().unwrap()
-            }
-        };
-        base_path.push("duplikatd");
+    fn base_state_path() -> std::path::PathBuf {
+        let mut base_path = DaemonConfig::get().state_dir();
         base_path.push("backups");
         base_path
     }
 
-    fn config_file(name: &str, filename: &str) -> std::path::PathBuf {
+    pub fn access_file() -> std::path::PathBuf {
+        let mut path = Self::daemon_config_path();
+        path.push("access");
+        path
+    }
+
+    pub fn secret_key_file() -> std::path::PathBuf {
+        let mut path = Self::daemon_config_path();
+        path.push("secret-key");
+        path
+    }
+
+    pub(crate) fn config_file(name: &str, filename: &str) -> std::path::PathBuf {
         let mut path = Self::base_config_path();
         path.push(name);
         path.push(filename);
         path
     }
 
-    pub fn repo_file(name: &str) -> std::path::PathBuf {
-        Self::config_file(name, "repo")
+    /// What the daemon keeps track of for a backup, as opposed to how it is
+    /// configured, which may be kept somewhere else.
+    pub(crate) fn state_file(name: &str, filename: &str) -> std::path::PathBuf {
+        let mut path = Self::base_state_path();
+        path.push(name);
+        path.push(filename);
+        path
     }
 
-    pub fn password_file(name: &str) -> std::path::PathBuf {
-        Self::config_file(name, "password")
+    pub fn last_scheduled_run_file(name: &str) -> std::path::PathBuf {
+        Self::state_file(name, "last_scheduled_run")
     }
 
-    pub fn include_file(name: &str) -> std::path::PathBuf {
-        Self::config_file(name, "include")
+    pub fn history_file(name: &str) -> std::path::PathBuf {
+        Self::state_file(name, "history")
     }
 
-    pub fn exclude_file(name: &str) -> std::path::PathBuf {
-        Self::config_file(name, "exclude")
+    pub fn log_file(name: &str) -> std::path::PathBuf {
+        Self::state_file(name, "log")
     }
+}
 
-    pub fn environment_file(name: &str) -> std::path::PathBuf {
-        Self::config_file(name, "environment")
-    }
+/// Writing fails once the client has gone away, which is not a reason to stop
+/// what was being done for it, so failures are ignored.
+pub(crate) async fn send_message<W: AsyncWrite + Unpin>(message: &ResticMessage, writer: &mut W) {
+    let line = serde_json::to_string(&message).unwrap() + "\n";
+    let _ = writer.write_all(line.as_bytes()).await;
 }
 
-#[allow(clippy::needless_lifetimes)]
-async fn send_message<'a>(message: &ResticMessage, writer: &mut WriteHalf<'a>) {
-    writer.write_all(
-        serde_json::to_string(&message)
-            .unwrap()
-            .as_bytes()
-    ).await.unwrap();
+/// Reports an error along with what it means, for clients that do not know
+/// about all of them.
+pub(crate) async fn send_error<W: AsyncWrite + Unpin>(error: &ServerError, writer: &mut W) {
+    send_json(
+        &json!({
+            "error": error,
+            "message": error.to_string(),
+        }),
+        writer
+    ).await;
+}
+
+pub(crate) async fn send_json<W: AsyncWrite + Unpin>(json: &serde_json::Value, writer: &mut W) {
+    let line = json.to_string() + "\n";
+    let _ = writer.write_all(line.as_bytes()).await;
+}
 
-    writer.write_all("\n".as_bytes()).await.unwrap();
+/// Turns an error message from restic into ours; newer versions of restic put
+/// a message in the error, older ones the fields of the Go error.
+fn error_message(name: &str, value: &serde_json::Value) -> ResticMessageError {
+    let message = match &value["error"] {
+        serde_json::Value::String(message) => message.clone(),
+        error => error["message"].as_str()
+            .map(|message| message.to_string())
+            .unwrap_or_else(|| error.to_string()),
+    };
+
+    ResticMessageError {
+        name: Some(name.to_string()),
+        message,
+        during: value["during"].as_str().unwrap_or_default().to_string(),
+        item: value["item"].as_str().unwrap_or_default().to_string(),
+    }
 }
 
-#[allow(clippy::needless_lifetimes)]
-async fn send_json<'a>(json: &serde_json::Value, writer: &mut WriteHalf<'a>) {
-    writer.write_all(json.to_string().as_bytes()
-    ).await.unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    writer.write_all("\n".as_bytes()).await.unwrap();
+    #[test]
+    fn snapshot_ids() {
+        assert!(Restic::validate_snapshot_id("abcdef01").is_ok());
+        assert!(Restic::validate_snapshot_id(&"0123456789abcdef".repeat(4)).is_ok());
+        assert!(Restic::validate_snapshot_id("latest").is_ok());
+        assert!(Restic::validate_snapshot_id("").is_err());
+        assert!(Restic::validate_snapshot_id("--password-command=sh").is_err());
+        assert!(Restic::validate_snapshot_id("abc def").is_err());
+    }
+
+    #[test]
+    fn certificates() {
+        let mut backup = Backup {
+            name: "home".to_string(),
+            repository: "rest:https://nas:8000/home".parse().unwrap(),
+            credentials: Credentials::None,
+            ca_cert: Some("/etc/ca.pem".into()),
+            client_cert: None,
+            rclone: None,
+            password: "pass".into(),
+            include: vec![],
+            exclude: vec![],
+            retention: None,
+            schedule: None,
+        };
+        assert!(Configuration::validate_repository(&backup).is_ok());
+
+        backup.client_cert = Some("/x\nLD_PRELOAD=/tmp/evil.so".into());
+        assert!(Configuration::validate_repository(&backup).is_err());
+    }
+
+    #[test]
+    fn names_from_clients() {
+        let message = ClientMessage::from_http_request("DELETE", "/v1/backups/..%2F..%2Fetc", b"").unwrap();
+        let name = match message {
+            ClientMessage::DeleteBackup(delete) => delete.name,
+            _ => panic!("Expected a delete request"),
+        };
+        assert_eq!(name, "../../etc");
+        assert!(!Configuration::exists(&name));
+        assert!(matches!(
+            server_error(&Configuration::ensure_exists(&name).unwrap_err(), Operation::DeleteBackup),
+            ServerError::InvalidName { .. }
+        ));
+
+        for name in &["..", ".", "a/b", "", "a\nb"] {
+            assert!(Configuration::ensure_exists(name).is_err());
+            assert!(Configuration::document_for_name(name).is_err());
+        }
+    }
 }
@@ -0,0 +1,363 @@
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, OnceLock};
+use anyhow::Result;
+use duplikat_types::{Operation, RepositoryKind, ServerError};
+use log::info;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdout, Command};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use crate::config::DaemonConfig;
+use crate::logger;
+use crate::restic::Configuration;
+
+/// How many restic processes may run at the same time unless configured
+/// otherwise; the rest wait for their turn.
+pub(crate) const DEFAULT_MAX_PROCESSES: usize = 4;
+
+static PERMITS: OnceLock<Arc<Semaphore>> = OnceLock::new();
+
+fn permits() -> Arc<Semaphore> {
+    PERMITS.get_or_init(|| Arc::new(Semaphore::new(DEFAULT_MAX_PROCESSES))).clone()
+}
+
+// What restic and the tools it uses for backends print when they cannot get
+// to the repository, or are not let in.
+const BACKEND_AUTH_ERRORS: &[&str] = &[
+    "401 Unauthorized",
+    "403 Forbidden",
+    "AccessDenied",
+    "InvalidAccessKeyId",
+    "SignatureDoesNotMatch",
+    "bad_auth_token",
+    "unauthorized",
+    "Permission denied (publickey",
+];
+const UNREACHABLE_ERRORS: &[&str] = &[
+    "Is there a repository at the following location?",
+    "repository does not exist",
+    "no such host",
+    "connection refused",
+    "i/o timeout",
+    "network is unreachable",
+    "Could not resolve hostname",
+    "Connection closed by remote host",
+];
+
+/// How restic failed, as far as can be told from its exit code and from what
+/// it printed; older versions use 1 for everything that is not a partial
+/// snapshot.
+#[derive(Error, Debug)]
+pub(crate) enum ResticError {
+    /// Exit code 3: a snapshot was made, but some files could not be read.
+    #[error("{0}")]
+    Incomplete(String),
+    #[error("{0}")]
+    Locked(String),
+    #[error("{0}")]
+    WrongPassword(String),
+    #[error("{0}")]
+    Unreachable(String),
+    #[error("{0}")]
+    BackendAuth(String),
+    #[error("restic could not be found")]
+    Missing,
+    /// Exit code 1, or anything else we have no better idea about.
+    #[error("{0}")]
+    Failed(String),
+}
+
+impl ResticError {
+    /// None if restic succeeded.
+    pub(crate) fn from_exit(status: ExitStatus, stderr: &str) -> Option<Self> {
+        if status.success() {
+            return None;
+        }
+
+        let mentions = |patterns: &[&str]| patterns.iter().any(|pattern| stderr.contains(pattern));
+        let message = failure_message(status, stderr);
+        let error = match status.code() {
+            Some(3) => ResticError::Incomplete(message),
+            Some(10) => ResticError::Unreachable(message),
+            Some(11) => ResticError::Locked(message),
+            Some(12) => ResticError::WrongPassword(message),
+            _ if stderr.contains("wrong password or no key found") => ResticError::WrongPassword(message),
+            _ if stderr.contains("unable to create lock") || stderr.contains("repository is already locked") => ResticError::Locked(message),
+            _ if mentions(BACKEND_AUTH_ERRORS) => ResticError::BackendAuth(message),
+            _ if mentions(UNREACHABLE_ERRORS) => ResticError::Unreachable(message),
+            _ => ResticError::Failed(message),
+        };
+        Some(error)
+    }
+
+    /// The error to report to clients, plain failures being reported as having
+    /// happened while doing the given operation.
+    pub(crate) fn to_server_error(&self, operation: Operation) -> ServerError {
+        match self {
+            ResticError::Incomplete(detail) => ServerError::IncompleteSnapshot { detail: detail.clone() },
+            ResticError::Locked(detail) => ServerError::RepositoryLocked { detail: detail.clone() },
+            ResticError::WrongPassword(detail) => ServerError::WrongPassword { detail: detail.clone() },
+            ResticError::Unreachable(detail) => ServerError::RepositoryUnreachable { detail: detail.clone() },
+            ResticError::BackendAuth(detail) => ServerError::BackendAuth { detail: detail.clone() },
+            ResticError::Missing => ServerError::ResticMissing,
+            ResticError::Failed(detail) => ServerError::Failed { operation, detail: detail.clone() },
+        }
+    }
+}
+
+/// Reports what went wrong as precisely as we can tell, anything we know
+/// nothing about as a failure of the given operation.
+pub(crate) fn server_error(error: &anyhow::Error, operation: Operation) -> ServerError {
+    if let Some(error) = error.downcast_ref::<ServerError>() {
+        return error.clone();
+    }
+
+    if let Some(error) = error.downcast_ref::<ResticError>() {
+        return error.to_server_error(operation);
+    }
+
+    let detail = error.to_string().trim().to_string();
+    match error.downcast_ref::<std::io::Error>() {
+        Some(io_error) if io_error.kind() == std::io::ErrorKind::PermissionDenied => {
+            ServerError::PermissionDenied { detail }
+        },
+        _ => ServerError::Failed { operation, detail },
+    }
+}
+
+/// Everything a restic process printed, once it is done.
+pub(crate) struct ResticOutput {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl ResticOutput {
+    /// Turns an unsuccessful run into a ResticError.
+    pub(crate) fn check(self) -> Result<Self> {
+        match ResticError::from_exit(self.status, &self.stderr) {
+            Some(error) => Err(error.into()),
+            None => Ok(self),
+        }
+    }
+}
+
+/// restic says why it gave up on a line of its own, often after a lot of
+/// warnings; without one all of stderr is the best we have.
+pub(crate) fn failure_message(status: ExitStatus, stderr: &str) -> String {
+    if let Some(fatal) = stderr.lines().rev().find(|line| line.starts_with("Fatal: ")) {
+        return fatal.trim().to_string();
+    }
+
+    match stderr.trim() {
+        "" => format!("restic {}", status),
+        stderr => stderr.to_string(),
+    }
+}
+
+/// A restic process whose output is read as it goes.
+pub(crate) struct ResticProcess {
+    child: Child,
+    stdout: Lines<BufReader<ChildStdout>>,
+    stderr: JoinHandle<String>,
+    _slot: OwnedSemaphorePermit,
+}
+
+impl ResticProcess {
+    pub(crate) fn id(&self) -> Option<u32> {
+        self.child.id()
+    }
+
+    pub(crate) async fn next_line(&mut self) -> Result<Option<String>> {
+        Ok(self.stdout.next_line().await?)
+    }
+
+    /// Waits for restic to exit, giving back its status and stderr.
+    pub(crate) async fn wait(mut self) -> Result<(ExitStatus, String)> {
+        // Whatever is left on stdout is of no interest, but restic must not
+        // block writing it.
+        while self.stdout.next_line().await?.is_some() {}
+
+        let status = self.child.wait().await?;
+        let stderr = self.stderr.await.unwrap_or_default();
+        Ok((status, stderr))
+    }
+}
+
+pub(crate) struct Runner {}
+
+impl Runner {
+    /// Must be called before restic is first run to have any effect.
+    pub(crate) fn set_max_processes(max: usize) {
+        let _ = PERMITS.set(Arc::new(Semaphore::new(max.max(1))));
+    }
+
+    /// A restic command for the given backup, pointed at its repository and
+    /// with the password and environment it needs. The password goes in the
+    /// environment, since it may not be in a file restic could read.
+    pub(crate) async fn command(name: &str, args: &[&str]) -> Result<Command> {
+        Configuration::ensure_exists(name)?;
+
+        let document = Configuration::document_for_name(name)?;
+        let password = Configuration::password_for_name(name)?;
+        let environment = Configuration::environment_for_name(name).await;
+        let config = DaemonConfig::get();
+        let mut command = Command::new(&config.restic);
+        command
+            .envs(environment)
+            .env("RESTIC_REPOSITORY", document.repository.to_string())
+            .env("RESTIC_PASSWORD", password.expose())
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+
+        if let Some(ca_cert) = &document.ca_cert {
+            command.env("RESTIC_CACERT", ca_cert);
+        }
+        if let Some(client_cert) = &document.client_cert {
+            command.env("RESTIC_TLS_CLIENT_CERT", client_cert);
+        }
+
+        // Options go before the arguments, which may end with `--` and paths.
+        if let Some(cache_dir) = &config.cache_dir {
+            command.arg("--cache-dir").arg(cache_dir);
+        }
+        if document.repository.kind == RepositoryKind::Rclone {
+            if let Some(rclone_config) = document.rclone.as_ref().and_then(|rclone| rclone.config.as_ref()) {
+                command.env("RCLONE_CONFIG", rclone_config);
+            }
+            if let Some(program) = &config.rclone_program {
+                command.arg("-o").arg(format!("rclone.program={}", program.display()));
+            }
+            if let Some(rclone_args) = &config.rclone_args {
+                command.arg("-o").arg(format!("rclone.args={}", rclone_args));
+            }
+        }
+        command.args(args);
+
+        Ok(command)
+    }
+
+    /// Waits until another restic process may be started.
+    pub(crate) async fn slot() -> Result<OwnedSemaphorePermit> {
+        Ok(permits().acquire_owned().await?)
+    }
+
+    /// Waits for a free slot, then starts restic for the given backup.
+    pub(crate) async fn spawn(name: &str, command: Command) -> Result<ResticProcess> {
+        Self::spawn_in(name, Self::slot().await?, command)
+    }
+
+    /// Starts restic in a slot that was already obtained, leaving stdout to
+    /// be read line by line. Stderr is collected on the side, and goes to the
+    /// log of the backup.
+    pub(crate) fn spawn_in(name: &str, slot: OwnedSemaphorePermit, mut command: Command) -> Result<ResticProcess> {
+        let mut child = command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|error| match error.kind() {
+                std::io::ErrorKind::NotFound => ResticError::Missing.into(),
+                _ => anyhow::Error::from(error),
+            })?;
+
+        let stdout = BufReader::new(child.stdout.take().expect("Failed to open stdout")).lines();
+
+        let stderr = child.stderr.take().expect("Failed to open stderr");
+        let target = logger::target(name);
+        let stderr = tokio::spawn(async move {
+            let mut output = String::new();
+            let mut lines = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                info!(target: &target, "restic: {}", line);
+                output.push_str(&line);
+                output.push('\n');
+            }
+            output
+        });
+
+        Ok(ResticProcess {
+            child,
+            stdout,
+            stderr,
+            _slot: slot,
+        })
+    }
+
+    /// Runs restic to completion, collecting all of its output.
+    pub(crate) async fn output(name: &str, command: Command) -> Result<ResticOutput> {
+        let mut process = Self::spawn(name, command).await?;
+
+        let mut stdout = String::new();
+        while let Some(line) = process.next_line().await? {
+            stdout.push_str(&line);
+            stdout.push('\n');
+        }
+
+        let (status, stderr) = process.wait().await?;
+        Ok(ResticOutput { status, stdout, stderr })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+    use super::*;
+
+    #[test]
+    fn failure_messages() {
+        let status = ExitStatus::from_raw(1 << 8);
+        assert_eq!(
+            failure_message(status, "Fatal: wrong password or no key found\n"),
+            "Fatal: wrong password or no key found"
+        );
+        assert_eq!(failure_message(status, "\n"), "restic exit status: 1");
+        assert_eq!(
+            failure_message(status, "error: lstat /home/kov/gone: no such file or directory\nFatal: unable to save snapshot: disk full\n"),
+            "Fatal: unable to save snapshot: disk full"
+        );
+    }
+
+    #[test]
+    fn restic_errors() {
+        let exit = |code: i32| ExitStatus::from_raw(code << 8);
+
+        assert!(ResticError::from_exit(exit(0), "").is_none());
+        assert!(matches!(
+            ResticError::from_exit(exit(3), "Warning: at least one source file could not be read\n"),
+            Some(ResticError::Incomplete(_))
+        ));
+        assert!(matches!(
+            ResticError::from_exit(exit(1), "Fatal: wrong password or no key found\n"),
+            Some(ResticError::WrongPassword(_))
+        ));
+        assert!(matches!(ResticError::from_exit(exit(12), ""), Some(ResticError::WrongPassword(_))));
+        assert!(matches!(
+            ResticError::from_exit(exit(1), "unable to create lock in backend: repository is already locked by PID 1234\n"),
+            Some(ResticError::Locked(_))
+        ));
+        assert!(matches!(
+            ResticError::from_exit(exit(1), "Fatal: unable to open config file\n"),
+            Some(ResticError::Failed(_))
+        ));
+
+        assert!(matches!(
+            ResticError::from_exit(exit(1), "Fatal: unable to open config file: Stat: 403 Forbidden\n"),
+            Some(ResticError::BackendAuth(_))
+        ));
+        assert!(matches!(
+            ResticError::from_exit(exit(1), "Fatal: unable to open config file: Stat: stat /srv/backups/config: no such file or directory\nIs there a repository at the following location?\n"),
+            Some(ResticError::Unreachable(_))
+        ));
+
+        let error = anyhow::Error::from(ResticError::Locked("locked".to_string()));
+        assert!(matches!(server_error(&error, Operation::ListSnapshots), ServerError::RepositoryLocked { .. }));
+        let error = anyhow::Error::from(ServerError::NotFound { name: "home".to_string() });
+        assert!(matches!(server_error(&error, Operation::ListSnapshots), ServerError::NotFound { .. }));
+        let error = anyhow::anyhow!("Malformed schedule");
+        assert!(matches!(
+            server_error(&error, Operation::ListSnapshots),
+            ServerError::Failed { operation: Operation::ListSnapshots, .. }
+        ));
+    }
+}
@@ -0,0 +1,302 @@
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::time::Duration;
+use anyhow::{Error, Result, anyhow, bail};
+use chrono::{Datelike, DateTime, Local, LocalResult, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, TimeZone, Timelike, Weekday};
+use duplikat_types::*;
+use log::{info, warn};
+use crate::jobs::Jobs;
+use crate::logger;
+use crate::restic::{Configuration, Restic};
+
+// We check the wall clock often instead of sleeping until the next run, so
+// that runs missed while the machine was suspended are noticed on resume.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, PartialEq)]
+enum Calendar {
+    Hourly { minute: u32 },
+    Daily { time: NaiveTime },
+    Weekly { weekday: Weekday, time: NaiveTime },
+    Monthly { day: u32, time: NaiveTime },
+}
+
+fn parse_time(string: Option<&str>) -> Result<NaiveTime> {
+    match string {
+        Some(string) => Ok(NaiveTime::parse_from_str(string, "%H:%M")?),
+        None => Ok(NaiveTime::MIN),
+    }
+}
+
+impl FromStr for Calendar {
+    type Err = Error;
+
+    fn from_str(expression: &str) -> Result<Self> {
+        let mut parts = expression.split_whitespace();
+        let calendar = match parts.next() {
+            Some("hourly") => {
+                let minute = match parts.next() {
+                    Some(minute) => minute.trim_start_matches(':').parse::<u32>()?,
+                    None => 0,
+                };
+                if minute > 59 {
+                    bail!("Bad minute in schedule: {}", minute);
+                }
+                Calendar::Hourly { minute }
+            },
+            Some("daily") => Calendar::Daily { time: parse_time(parts.next())? },
+            Some("weekly") => {
+                let weekday = match parts.next() {
+                    Some(weekday) => Weekday::from_str(weekday)
+                        .map_err(|_| Error::msg(format!("Bad weekday in schedule: {}", weekday)))?,
+                    None => bail!("Weekly schedules need a day of the week"),
+                };
+                Calendar::Weekly { weekday, time: parse_time(parts.next())? }
+            },
+            Some("monthly") => {
+                let day = match parts.next() {
+                    Some(day) => day.parse::<u32>()?,
+                    None => bail!("Monthly schedules need a day of the month"),
+                };
+                if !(1..=31).contains(&day) {
+                    bail!("Bad day of the month in schedule: {}", day);
+                }
+                Calendar::Monthly { day, time: parse_time(parts.next())? }
+            },
+            _ => bail!("Unknown schedule: {}", expression),
+        };
+
+        if parts.next().is_some() {
+            bail!("Trailing garbage in schedule: {}", expression);
+        }
+
+        Ok(calendar)
+    }
+}
+
+/// The first moment the given local time happens, if it does at all; chrono
+/// does not always list the two of a time that happens twice in order.
+fn earliest(time: &NaiveDateTime) -> Option<DateTime<Local>> {
+    match Local.from_local_datetime(time) {
+        LocalResult::Single(time) => Some(time),
+        LocalResult::Ambiguous(one, other) => Some(one.min(other)),
+        LocalResult::None => None,
+    }
+}
+
+impl Calendar {
+    fn next_after(&self, after: DateTime<Local>) -> DateTime<Local> {
+        // Walk forward hour by hour in local time, skipping those that do not
+        // exist and taking the first of those that happen twice.
+        if let Calendar::Hourly { minute } = self {
+            let hour = NaiveTime::from_hms_opt(after.hour(), *minute, 0).unwrap_or(NaiveTime::MIN);
+            let mut time = after.date_naive().and_time(hour);
+            loop {
+                if let Some(next) = earliest(&time) {
+                    if next > after {
+                        return next;
+                    }
+                }
+
+                time = time.checked_add_signed(TimeDelta::hours(1)).unwrap_or(NaiveDateTime::MAX);
+            }
+        }
+
+        // Walk forward day by day until we find one that matches. Local times
+        // that do not exist because of DST changes are skipped.
+        let mut date = after.date_naive();
+        loop {
+            let time = match self {
+                Calendar::Daily { time } => Some(time),
+                Calendar::Weekly { weekday, time } if date.weekday() == *weekday => Some(time),
+                Calendar::Monthly { day, time } if date.day() == *day => Some(time),
+                _ => None,
+            };
+
+            if let Some(time) = time {
+                if let Some(next) = earliest(&date.and_time(*time)) {
+                    if next > after {
+                        return next;
+                    }
+                }
+            }
+
+            date = date.succ_opt().unwrap_or(NaiveDate::MAX);
+        }
+    }
+}
+
+pub(crate) struct Scheduler {}
+
+impl Scheduler {
+    pub(crate) fn validate(schedule: &Schedule) -> Result<()> {
+        match schedule {
+            Schedule::Interval { seconds: 0 } => bail!("Schedule interval cannot be zero"),
+            Schedule::Interval { seconds } if *seconds > MAX_SCHEDULE_INTERVAL => {
+                bail!("Schedule interval cannot be longer than a year")
+            },
+            Schedule::Interval { .. } => Ok(()),
+            Schedule::Calendar { expression } => Calendar::from_str(expression).map(|_| ()),
+        }
+    }
+
+    pub(crate) fn next_run(schedule: &Schedule, last_run: DateTime<Local>) -> Result<DateTime<Local>> {
+        match schedule {
+            Schedule::Interval { seconds } => i64::try_from(*seconds).ok()
+                .and_then(TimeDelta::try_seconds)
+                .and_then(|interval| last_run.checked_add_signed(interval))
+                .ok_or_else(|| anyhow!("Schedule interval of {} seconds is too long", seconds)),
+            Schedule::Calendar { expression } => Ok(Calendar::from_str(expression)?.next_after(last_run)),
+        }
+    }
+
+    /// When the given backup is going to run next, if it is scheduled at all.
+    pub(crate) fn next_run_for(name: &str) -> Option<DateTime<Local>> {
+        let schedule = Configuration::schedule_for_name(name).ok()??;
+        let last_run = Configuration::last_scheduled_run(name).unwrap_or_else(Local::now);
+        Self::next_run(&schedule, last_run).ok()
+    }
+
+    pub(crate) fn start() {
+        tokio::spawn(async {
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                Self::run_due().await;
+            }
+        });
+    }
+
+    async fn run_due() {
+        let names = match Configuration::names().await {
+            Ok(names) => names,
+            Err(error) => { warn!("{:#?}", error); return },
+        };
+
+        let now = Local::now();
+        for name in names {
+            let schedule = match Configuration::schedule_for_name(&name) {
+                Ok(Some(schedule)) => schedule,
+                Ok(None) => continue,
+                Err(error) => { warn!("Bad schedule for {}: {:#?}", name, error); continue },
+            };
+
+            // Backups we have never seen before start counting from now, rather
+            // than all running at once.
+            let last_run = match Configuration::last_scheduled_run(&name) {
+                Some(last_run) => last_run,
+                None => {
+                    if let Err(error) = Configuration::set_last_scheduled_run(&name, now) {
+                        warn!("{:#?}", error);
+                    }
+                    continue;
+                },
+            };
+
+            match Self::next_run(&schedule, last_run) {
+                Ok(next_run) if next_run <= now => (),
+                Ok(_) => continue,
+                Err(error) => { warn!("Bad schedule for {}: {:#?}", name, error); continue },
+            }
+
+            if Jobs::is_running(&name) {
+                continue;
+            }
+
+            // Runs that were missed while we were not running are collapsed
+            // into this one, as the next one is computed from now.
+            if let Err(error) = Configuration::set_last_scheduled_run(&name, now) {
+                warn!("{:#?}", error);
+                continue;
+            }
+
+            // Starting may have to wait for other restic processes to finish,
+            // which should not hold up the rest of the schedule.
+            let target = logger::target(&name);
+            info!(target: &target, "Starting scheduled backup");
+            tokio::spawn(async move {
+                if let Err(error) = Restic::start_backup(&name, RunTrigger::Scheduled).await {
+                    warn!(target: &target, "Failed to start scheduled backup: {:#}", error);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The DST changes below are those of this zone; every test sets it so
+    // they agree on what local times mean when run in parallel.
+    fn local(string: &str) -> DateTime<Local> {
+        std::env::set_var("TZ", "Europe/Berlin");
+        earliest(&NaiveDateTime::parse_from_str(string, "%Y-%m-%d %H:%M").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn calendar_parsing() {
+        assert_eq!(
+            Calendar::from_str("weekly mon 03:30").unwrap(),
+            Calendar::Weekly {
+                weekday: Weekday::Mon,
+                time: NaiveTime::from_hms_opt(3, 30, 0).unwrap(),
+            }
+        );
+        assert_eq!(Calendar::from_str("hourly :15").unwrap(), Calendar::Hourly { minute: 15 });
+        assert!(Calendar::from_str("daily 25:00").is_err());
+        assert!(Calendar::from_str("monthly 32").is_err());
+        assert!(Calendar::from_str("sometimes").is_err());
+    }
+
+    #[test]
+    fn calendar_next_run() {
+        // 2021-07-19 was a Monday.
+        let after = local("2021-07-19 10:00");
+        let next = |expression: &str| Calendar::from_str(expression).unwrap().next_after(after);
+
+        assert_eq!(next("hourly :15"), local("2021-07-19 10:15"));
+        assert_eq!(next("hourly"), local("2021-07-19 11:00"));
+        assert_eq!(next("daily 02:00"), local("2021-07-20 02:00"));
+        assert_eq!(next("daily 12:00"), local("2021-07-19 12:00"));
+        assert_eq!(next("weekly mon 03:30"), local("2021-07-26 03:30"));
+        assert_eq!(next("weekly wed"), local("2021-07-21 00:00"));
+        assert_eq!(next("monthly 31 04:00"), local("2021-07-31 04:00"));
+        assert_eq!(
+            Calendar::from_str("monthly 31").unwrap().next_after(local("2021-08-31 05:00")),
+            local("2021-10-31 00:00")
+        );
+    }
+
+    #[test]
+    fn calendar_dst_changes() {
+        // Clocks went from 02:00 to 03:00 on 2021-03-28, and from 03:00 back
+        // to 02:00 on 2021-10-31.
+        let hourly = Calendar::from_str("hourly :15").unwrap();
+        assert_eq!(hourly.next_after(local("2021-03-28 01:30")), local("2021-03-28 03:15"));
+        assert_eq!(hourly.next_after(local("2021-10-31 01:30")), local("2021-10-31 02:15"));
+
+        let repeated = local("2021-10-31 02:15") + TimeDelta::hours(1);
+        assert_eq!(hourly.next_after(repeated), local("2021-10-31 03:15"));
+
+        let daily = Calendar::from_str("daily 02:30").unwrap();
+        assert_eq!(daily.next_after(local("2021-03-27 12:00")), local("2021-03-29 02:30"));
+        assert_eq!(daily.next_after(local("2021-10-30 12:00")), local("2021-10-31 02:30"));
+    }
+
+    #[test]
+    fn interval_next_run() {
+        let schedule = Schedule::Interval { seconds: 6 * 60 * 60 };
+        assert_eq!(
+            Scheduler::next_run(&schedule, local("2021-07-19 22:00")).unwrap(),
+            local("2021-07-20 04:00")
+        );
+
+        let schedule = Schedule::Interval { seconds: u64::MAX };
+        assert!(Scheduler::validate(&schedule).is_err());
+        assert!(Scheduler::next_run(&schedule, local("2021-07-19 22:00")).is_err());
+        let schedule = Schedule::Interval { seconds: i64::MAX as u64 / 1000 + 1 };
+        assert!(Scheduler::next_run(&schedule, local("2021-07-19 22:00")).is_err());
+        assert!(Scheduler::validate(&Schedule::Interval { seconds: MAX_SCHEDULE_INTERVAL }).is_ok());
+    }
+}
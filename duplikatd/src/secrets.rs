@@ -0,0 +1,370 @@
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+use anyhow::{Context, Result, anyhow, bail};
+use chacha20poly1305::{Key, KeyInit, XChaCha20Poly1305, XNonce};
+use chacha20poly1305::aead::{Aead, AeadCore, OsRng, Payload};
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+use crate::config::{remove_if_exists, write_atomically};
+use crate::restic::Configuration;
+
+static STORE: OnceLock<SecretStore> = OnceLock::new();
+
+// Marks files written by the encrypted store, in case the format ever changes.
+const ENCRYPTED_MAGIC: &[u8] = b"DKS1";
+const NONCE_LENGTH: usize = 24;
+
+/// The parts of a backup's configuration that must not be readable by others.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SecretItem {
+    Password,
+    /// Holds the credentials for the storage, as restic reads them.
+    Environment,
+}
+
+impl SecretItem {
+    fn file_name(&self) -> &'static str {
+        match self {
+            SecretItem::Password => "password",
+            SecretItem::Environment => "environment",
+        }
+    }
+}
+
+/// Which store the daemon is configured to use.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SecretStoreKind {
+    #[default]
+    File,
+    Encrypted,
+    SecretService,
+}
+
+/// Where the secrets of backups are kept.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum SecretStore {
+    /// Plain files next to the rest of the configuration, only readable by
+    /// the user the daemon runs as.
+    File,
+    /// Files encrypted with a key that is generated on first use and kept in
+    /// the daemon's configuration directory, so copies of the backups'
+    /// configuration do not carry usable secrets with them.
+    Encrypted { key_file: PathBuf },
+    /// The user's keyring, through secret-tool. Meant for per-user daemons,
+    /// as the system one has no session to find it in.
+    SecretService { program: PathBuf },
+}
+
+impl SecretStore {
+    /// Must be called before secrets are first used to have any effect.
+    pub(crate) fn configure(kind: SecretStoreKind) {
+        let store = match kind {
+            SecretStoreKind::File => SecretStore::File,
+            SecretStoreKind::Encrypted => SecretStore::Encrypted { key_file: Configuration::secret_key_file() },
+            SecretStoreKind::SecretService => SecretStore::SecretService { program: PathBuf::from("secret-tool") },
+        };
+        let _ = STORE.set(store);
+    }
+
+    pub(crate) fn get() -> &'static SecretStore {
+        STORE.get_or_init(|| SecretStore::File)
+    }
+
+    /// Reads a secret of the given backup, if it has one. Secrets left
+    /// behind by other stores, such as the plain files older versions wrote,
+    /// are moved into this one as they are found.
+    pub(crate) fn read(&self, name: &str, item: SecretItem) -> Result<Option<String>> {
+        if let Some(secret) = self.fetch(name, item)? {
+            return Ok(Some(secret));
+        }
+
+        let previous_stores = [
+            SecretStore::File,
+            SecretStore::Encrypted { key_file: Configuration::secret_key_file() },
+        ];
+        for previous in previous_stores.iter().filter(|previous| *previous != self) {
+            if let Some(secret) = previous.fetch(name, item)? {
+                info!("Moving the {} of {} into the secret store", item.file_name(), name);
+                self.write(name, item, &secret)?;
+                previous.remove(name, item)?;
+                return Ok(Some(secret));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn fetch(&self, name: &str, item: SecretItem) -> Result<Option<String>> {
+        match self {
+            SecretStore::File => read_private_file(&Configuration::config_file(name, item.file_name())),
+            SecretStore::Encrypted { key_file } => {
+                match std::fs::read(Self::encrypted_file(name, item)) {
+                    Ok(contents) => Ok(Some(decrypt(&load_key(key_file)?, item, &contents)
+                        .with_context(|| format!("Could not decrypt the {} of {}", item.file_name(), name))?)),
+                    Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                    Err(error) => Err(error.into()),
+                }
+            },
+            SecretStore::SecretService { program } => Self::lookup(program, name, item),
+        }
+    }
+
+    pub(crate) fn write(&self, name: &str, item: SecretItem, secret: &str) -> Result<()> {
+        match self {
+            SecretStore::File => write_private_file(&Configuration::config_file(name, item.file_name()), secret.as_bytes()),
+            SecretStore::Encrypted { key_file } => {
+                let contents = encrypt(&load_key(key_file)?, item, secret)?;
+                write_private_file(&Self::encrypted_file(name, item), &contents)
+            },
+            SecretStore::SecretService { program } => {
+                let label = format!("Duplikat {} of {}", item.file_name(), name);
+                let mut child = Command::new(program)
+                    .arg("store")
+                    .arg("--label").arg(label)
+                    .args(Self::attributes(name, item))
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::piped())
+                    .spawn()
+                    .with_context(|| format!("Could not run {}", program.display()))?;
+                child.stdin.take().expect("Failed to open stdin").write_all(secret.as_bytes())?;
+
+                let output = child.wait_with_output()?;
+                if !output.status.success() {
+                    bail!("Could not store the {} of {}: {}", item.file_name(), name, String::from_utf8_lossy(&output.stderr).trim());
+                }
+                Ok(())
+            },
+        }
+    }
+
+    pub(crate) fn remove(&self, name: &str, item: SecretItem) -> Result<()> {
+        match self {
+            SecretStore::File => remove_if_exists(&Configuration::config_file(name, item.file_name())),
+            SecretStore::Encrypted { .. } => remove_if_exists(&Self::encrypted_file(name, item)),
+            SecretStore::SecretService { program } => {
+                // Clearing something that is not there is not an error.
+                let output = Command::new(program)
+                    .arg("clear")
+                    .args(Self::attributes(name, item))
+                    .stdin(Stdio::null())
+                    .output()
+                    .with_context(|| format!("Could not run {}", program.display()))?;
+                if !output.status.success() && !output.stderr.is_empty() {
+                    bail!("Could not clear the {} of {}: {}", item.file_name(), name, String::from_utf8_lossy(&output.stderr).trim());
+                }
+                Ok(())
+            },
+        }
+    }
+
+    /// Files follow the directory of the backup around, but the keyring
+    /// knows secrets by the name of the backup they belong to.
+    pub(crate) fn rename(&self, from: &str, to: &str) -> Result<()> {
+        if let SecretStore::SecretService { program } = self {
+            for item in &[SecretItem::Password, SecretItem::Environment] {
+                if let Some(secret) = Self::lookup(program, from, *item)? {
+                    self.write(to, *item, &secret)?;
+                    self.remove(from, *item)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn lookup(program: &Path, name: &str, item: SecretItem) -> Result<Option<String>> {
+        let output = Command::new(program)
+            .arg("lookup")
+            .args(Self::attributes(name, item))
+            .stdin(Stdio::null())
+            .output()
+            .with_context(|| format!("Could not run {}", program.display()))?;
+
+        // secret-tool says nothing and fails when there is no such secret.
+        match (output.status.success(), output.stderr.is_empty()) {
+            (true, _) => Ok(Some(String::from_utf8(output.stdout)?)),
+            (false, true) => Ok(None),
+            (false, false) => bail!("Could not look up the {} of {}: {}", item.file_name(), name, String::from_utf8_lossy(&output.stderr).trim()),
+        }
+    }
+
+    fn attributes(name: &str, item: SecretItem) -> Vec<&str> {
+        vec!["application", "duplikat", "backup", name, "item", item.file_name()]
+    }
+
+    fn encrypted_file(name: &str, item: SecretItem) -> PathBuf {
+        Configuration::config_file(name, &format!("{}.enc", item.file_name()))
+    }
+}
+
+/// Reads a file only the daemon should be able to read, tightening its
+/// permissions if they are too loose, as older versions left them.
+fn read_private_file(path: &Path) -> Result<Option<String>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    if permissions.mode() & 0o077 != 0 {
+        warn!("{} could be read by others, fixing its permissions", path.display());
+        permissions.set_mode(0o600);
+        std::fs::set_permissions(path, permissions)?;
+    }
+
+    Ok(Some(contents))
+}
+
+/// The secret is either the old one or the new one, never half of each, and
+/// nobody else can read it meanwhile.
+fn write_private_file(path: &Path, contents: &[u8]) -> Result<()> {
+    write_atomically(path, contents, 0o600)
+}
+
+/// The key the encrypted store uses, which is made up the first time it is
+/// needed.
+fn load_key(key_file: &Path) -> Result<Key> {
+    match std::fs::read(key_file) {
+        Ok(key) if key.len() == 32 => return Ok(*Key::from_slice(&key)),
+        Ok(_) => bail!("{} does not hold a valid key", key_file.display()),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => (),
+        Err(error) => return Err(error.into()),
+    }
+
+    let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+    match OpenOptions::new().write(true).create_new(true).mode(0o600).open(key_file) {
+        Ok(mut file) => {
+            file.write_all(&key)?;
+            file.sync_all()?;
+            info!("Created a new key for the secret store in {}", key_file.display());
+            Ok(key)
+        },
+        // Someone else got there first.
+        Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => load_key(key_file),
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// The item is authenticated along with the secret, so a password cannot be
+/// passed off as an environment or the other way around.
+fn encrypt(key: &Key, item: SecretItem, secret: &str) -> Result<Vec<u8>> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let payload = Payload { msg: secret.as_bytes(), aad: item.file_name().as_bytes() };
+    let ciphertext = XChaCha20Poly1305::new(key)
+        .encrypt(&nonce, payload)
+        .map_err(|_| anyhow!("Failed to encrypt the {}", item.file_name()))?;
+
+    Ok([ENCRYPTED_MAGIC, nonce.as_slice(), &ciphertext].concat())
+}
+
+fn decrypt(key: &Key, item: SecretItem, contents: &[u8]) -> Result<String> {
+    if !contents.starts_with(ENCRYPTED_MAGIC) || contents.len() < ENCRYPTED_MAGIC.len() + NONCE_LENGTH {
+        bail!("Not written by the encrypted store");
+    }
+
+    let (nonce, ciphertext) = contents[ENCRYPTED_MAGIC.len()..].split_at(NONCE_LENGTH);
+    let payload = Payload { msg: ciphertext, aad: item.file_name().as_bytes() };
+    let secret = XChaCha20Poly1305::new(key)
+        .decrypt(XNonce::from_slice(nonce), payload)
+        .map_err(|_| anyhow!("Wrong key, or the file was tampered with"))?;
+
+    Ok(String::from_utf8(secret)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temporary_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("duplikatd-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn private_files() {
+        let dir = temporary_dir("private");
+        let path = dir.join("password");
+
+        write_private_file(&path, b"hunter2").unwrap();
+        write_private_file(&path, b"correct horse").unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(read_private_file(&path).unwrap().as_deref(), Some("correct horse"));
+        assert!(!dir.join("password.new").exists());
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        read_private_file(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        assert_eq!(read_private_file(&dir.join("environment")).unwrap(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn encryption() {
+        let dir = temporary_dir("encryption");
+        let key = load_key(&dir.join("secret-key")).unwrap();
+        assert_eq!(load_key(&dir.join("secret-key")).unwrap(), key);
+
+        let contents = encrypt(&key, SecretItem::Password, "hunter2").unwrap();
+        assert!(!contents.windows(7).any(|window| window == b"hunter2"));
+        assert_eq!(decrypt(&key, SecretItem::Password, &contents).unwrap(), "hunter2");
+        assert_ne!(encrypt(&key, SecretItem::Password, "hunter2").unwrap(), contents);
+
+        assert!(decrypt(&key, SecretItem::Environment, &contents).is_err());
+        let other = XChaCha20Poly1305::generate_key(&mut OsRng);
+        assert!(decrypt(&other, SecretItem::Password, &contents).is_err());
+        let mut tampered = contents.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(decrypt(&key, SecretItem::Password, &tampered).is_err());
+        assert!(decrypt(&key, SecretItem::Password, b"hunter2").is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn secret_service() {
+        // Stands in for secret-tool, keeping each secret in a file named
+        // after its attributes.
+        let dir = temporary_dir("secret-service");
+        let program = dir.join("secret-tool");
+        std::fs::write(&program, r#"#!/bin/sh
+store="$(dirname "$0")/store"
+mkdir -p "$store"
+command=$1; shift
+[ "$command" = store ] && shift 2
+key=$(echo "$@" | tr ' /' '__')
+case $command in
+    store) cat > "$store/$key" ;;
+    lookup) [ -f "$store/$key" ] || exit 1; cat "$store/$key" ;;
+    clear) rm -f "$store/$key" ;;
+esac
+"#).unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let store = SecretStore::SecretService { program };
+        store.write("home", SecretItem::Password, "hunter2").unwrap();
+        store.write("home", SecretItem::Environment, "B2_ACCOUNT_KEY=secret\n").unwrap();
+        assert_eq!(SecretStore::lookup(&dir.join("secret-tool"), "home", SecretItem::Password).unwrap().as_deref(), Some("hunter2"));
+
+        store.rename("home", "house").unwrap();
+        assert_eq!(SecretStore::lookup(&dir.join("secret-tool"), "home", SecretItem::Password).unwrap(), None);
+        assert_eq!(
+            SecretStore::lookup(&dir.join("secret-tool"), "house", SecretItem::Environment).unwrap().as_deref(),
+            Some("B2_ACCOUNT_KEY=secret\n")
+        );
+
+        store.remove("house", SecretItem::Password).unwrap();
+        store.remove("house", SecretItem::Password).unwrap();
+        assert_eq!(SecretStore::lookup(&dir.join("secret-tool"), "house", SecretItem::Password).unwrap(), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}